@@ -3,7 +3,8 @@ use std::cell::RefCell;
 use regex::bytes::{CaptureLocations, Regex};
 use serde::Serialize;
 
-use crate::parse::{LBLogParser, ParseLogError, bytes_ser, optional_bytes_ser};
+use crate::diagnose::Diagnoser;
+use crate::parse::{LBLogParser, LogFields, ParseLogError, bytes_ser, optional_bytes_ser};
 
 #[derive(Serialize)]
 pub struct Log<'a> {
@@ -83,8 +84,67 @@ pub struct Log<'a> {
         serialize_with = "optional_bytes_ser"
     )]
     pub tid: Option<&'a [u8]>,
+
+    /// Undocumented space after http_version, which is not a field but is kept to render the
+    /// original line back
+    #[serde(skip)]
+    pub http_version_padding: &'a [u8],
+    /// Undocumented space before domain_name, which is kept likewise
+    #[serde(skip)]
+    pub domain_name_padding: &'a [u8],
+}
+
+impl LogFields for Log<'_> {
+    fn fields(&self) -> Vec<Option<&[u8]>> {
+        vec![
+            Some(self.r#type),
+            Some(self.time),
+            Some(self.elb),
+            Some(self.client_ip),
+            Some(self.client_port),
+            Some(self.target_ip_port),
+            Some(self.request_processing_time),
+            Some(self.target_processing_time),
+            Some(self.response_processing_time),
+            Some(self.elb_status_code),
+            Some(self.target_status_code),
+            Some(self.received_bytes),
+            Some(self.sent_bytes),
+            Some(self.http_method),
+            Some(self.url),
+            Some(self.http_version),
+            Some(self.user_agent),
+            Some(self.ssl_cipher),
+            Some(self.ssl_protocol),
+            Some(self.target_group_arn),
+            Some(self.trace_id),
+            Some(self.domain_name),
+            Some(self.chosen_cert_arn),
+            Some(self.matched_rule_priority),
+            Some(self.request_creation_time),
+            Some(self.actions_executed),
+            Some(self.redirect_url),
+            Some(self.error_reason),
+            Some(self.target_ip_port_list),
+            Some(self.target_status_code_list),
+            Some(self.classification),
+            Some(self.classification_reason),
+            self.tid,
+        ]
+    }
+
+    fn filler(&self, name: &str) -> Option<&[u8]> {
+        match name {
+            "http_version_padding" => Some(self.http_version_padding),
+            "domain_name_padding" => Some(self.domain_name_padding),
+            _ => None,
+        }
+    }
 }
 
+/// Diagnoser of lines which failed to parse, shared by every parser
+static DIAGNOSER: Diagnoser = Diagnoser::new(LogParser::REGEX, LogParser::FIELDS);
+
 pub struct LogParser {
     regex: Regex,
     locs: RefCell<CaptureLocations>,
@@ -128,14 +188,15 @@ impl LBLogParser for LogParser {
         "
             ([0-9A-Za-z-_]+)                                    # http method
             \x20
-            ((?:[^\n\\"]|\\"|\\\\|\\x[0-9a-fA-F]{2}(?:[0-9a-fA-F]{6})?)*?)       # URL
+            ((?:(?-u:[^\n\\"])|\\"|\\\\|\\x[0-9a-fA-F]{2}(?:[0-9a-fA-F]{6})?)*?)       # URL
+                                                                # MEMO: (?-u:...) accepts raw bytes which are not valid UTF-8, see `--invalid-utf8`
             \x20
             ((?:-|HTTP/[0-9.]+)?)                               # http version
                                                                 # MEMO: Contrary to the official document, we've observed that the HTTP version is sometimes missing in real world data
             \x20?                                               # MEMO: We've observed undocumented space character here in real world data
         "
         \x20
-        "((?:[^\n\\"]|\\"|\\\\|\\x[0-9a-fA-F]{2}(?:[0-9a-fA-F]{6})?)*)"         # user agent
+        "((?:(?-u:[^\n\\"])|\\"|\\\\|\\x[0-9a-fA-F]{2}(?:[0-9a-fA-F]{6})?)*)"         # user agent
         \x20
         ([0-9A-Z-_]+)                                           # ssl cipher
         \x20
@@ -160,7 +221,7 @@ impl LBLogParser for LogParser {
             -?                                                  # MEMO: We've observed undocumented empty actions_executed in real world data
         )"                                                      # actions_executed, https://docs.aws.amazon.com/elasticloadbalancing/latest/application/load-balancer-access-logs.html#actions-taken
         \x20
-        "((?:[^\n\\"]|\\"|\\\\|\\x[0-9a-fA-F]{2}(?:[0-9a-fA-F]{6})?)*|-)"             # redirect_url
+        "((?:(?-u:[^\n\\"])|\\"|\\\\|\\x[0-9a-fA-F]{2}(?:[0-9a-fA-F]{6})?)*|-)"             # redirect_url
         \x20
         "([a-zA-Z]+|-)"                                         # error_reason, https://docs.aws.amazon.com/elasticloadbalancing/latest/application/load-balancer-access-logs.html#error-reason-codes
         \x20
@@ -189,6 +250,52 @@ impl LBLogParser for LogParser {
         \x0A?
         $
     "#;
+    const FIELDS: &'static [&'static str] = &[
+        "type",
+        "time",
+        "elb",
+        "client_ip",
+        "client_port",
+        "target_ip_port",
+        "request_processing_time",
+        "target_processing_time",
+        "response_processing_time",
+        "elb_status_code",
+        "target_status_code",
+        "received_bytes",
+        "sent_bytes",
+        "http_method",
+        "url",
+        "http_version",
+        "user_agent",
+        "ssl_cipher",
+        "ssl_protocol",
+        "target_group_arn",
+        "trace_id",
+        "domain_name",
+        "chosen_cert_arn",
+        "matched_rule_priority",
+        "request_creation_time",
+        "actions_executed",
+        "redirect_url",
+        "error_reason",
+        "target_ip_port_list",
+        "target_status_code_list",
+        "classification",
+        "classification_reason",
+        "tid",
+    ];
+    const TEMPLATE: &'static str = concat!(
+        r#"{type} {time} {elb} {client_ip}:{client_port} {target_ip_port} "#,
+        r#"{request_processing_time} {target_processing_time} {response_processing_time} "#,
+        r#"{elb_status_code} {target_status_code} {received_bytes} {sent_bytes} "#,
+        r#""{http_method} {url} {http_version}{http_version_padding}" "{user_agent}" "#,
+        r#"{ssl_cipher} {ssl_protocol} {target_group_arn} "{trace_id}" "#,
+        r#""{domain_name_padding}{domain_name}" "{chosen_cert_arn}" "#,
+        r#"{matched_rule_priority} {request_creation_time} "{actions_executed}" "#,
+        r#""{redirect_url}" "{error_reason}" "{target_ip_port_list}" "#,
+        r#""{target_status_code_list}" "{classification}" "{classification_reason}"[ {tid}]"#,
+    );
 
     fn new() -> Self {
         let regex = Regex::new(Self::REGEX).unwrap();
@@ -200,10 +307,14 @@ impl LBLogParser for LogParser {
         let mut locs = self.locs.borrow_mut();
         self.regex
             .captures_read(&mut locs, log)
-            .ok_or_else(|| ParseLogError::InvalidLogFormat(log.to_owned()))?;
+            .ok_or_else(|| DIAGNOSER.error(log))?;
 
         let optional = |i| locs.get(i).map(|(start, end)| &log[start..end]);
         let s = |i| optional(i).unwrap();
+        // Each of the undocumented spaces is right next to its field, if it exists
+        let (_, http_version_end) = locs.get(16).unwrap();
+        let (domain_name_start, _) = locs.get(22).unwrap();
+        let space = |i: usize| usize::from(log[i] == b' ');
 
         Ok(Log {
             r#type: s(1),
@@ -239,6 +350,10 @@ impl LBLogParser for LogParser {
             classification: s(31),
             classification_reason: s(32),
             tid: optional(33),
+            http_version_padding: &log
+                [http_version_end..http_version_end + space(http_version_end)],
+            domain_name_padding: &log
+                [domain_name_start - space(domain_name_start - 1)..domain_name_start],
         })
     }
 }
@@ -246,11 +361,17 @@ impl LBLogParser for LogParser {
 #[test]
 fn test_log_parser() -> Result<(), ParseLogError> {
     let parser = LogParser::new();
-    let t = |input, expected| -> Result<(), ParseLogError> {
-        assert_eq!(
-            serde_json::to_string(&mut parser.parse(input)?).unwrap(),
-            expected
-        );
+    // Parses `input`, and renders it back as is
+    let t = |input: &[u8], expected| -> Result<(), ParseLogError> {
+        let log = parser.parse(input)?;
+        assert_eq!(serde_json::to_string(&log).unwrap(), expected);
+        let raw = crate::format::FormatConfig {
+            format: crate::format::Format::Raw,
+            ..Default::default()
+        };
+        let rendered = crate::format::render_log::<LogParser>(&log, &raw).unwrap();
+        let line = input.strip_suffix(b"\n").unwrap_or(input);
+        assert_eq!(rendered, String::from_utf8_lossy(line));
         Ok(())
     };
 
@@ -346,10 +467,23 @@ fn test_log_parser() -> Result<(), ParseLogError> {
     //
     // Error cases
     //
-    let Err(ParseLogError::InvalidLogFormat(_)) = parser.parse(b"h2 2024-05-28T13:34:14.804475Z")
-    else {
+    let Err(err) = parser.parse(b"h2 2024-05-28T13:34:14.804475Z") else {
         panic!("Expected error for incomplete log line");
     };
+    let diagnostic = err.diagnostic();
+    assert_eq!(diagnostic.field, Some((3, "elb")));
+    assert_eq!(diagnostic.position, Some(30));
+
+    let Err(err) = parser.parse(
+        br#"h2 2020-01-11T01:11:10.111111Z app/myalb/0123456789abcdef 1.123.123.123:12345 10.0.1.100:80 0.000 0.159 0.000 200 200 315 488 "GET https://example.com:443/ HTTP/2.0" "curl/8.0" ECDHE-RSA-AES128-GCM-SHA256 TLS1.3 arn:aws:elasticloadbalancing:ap-northeast-3:012345678901:targetgroup/myalb/0123456789abcdef "Root=1-abcd0123-0123456789abcdef01234567" "example.com" "session-reused" 1 2020-01-11T01:11:10.111111Z "forward" "-" "-" "10.0.1.100:80" "200" "-" "-""#,
+    ) else {
+        panic!("Expected error for invalid ssl_protocol");
+    };
+    let diagnostic = err.diagnostic();
+    assert_eq!(
+        diagnostic.to_string(),
+        "field `ssl_protocol` (19): expected `TLSv[0-9.]+|-`, found `TLS1.3`"
+    );
 
     Ok(())
 }
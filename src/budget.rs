@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::parse::ParseLogError;
+
+#[derive(Error, Debug)]
+pub(crate) enum ErrorBudgetExceeded {
+    #[error("Too many parse errors: more than {max} lines failed to parse")]
+    Count { max: u64 },
+    #[error("Too many parse errors: {errors} of {lines} lines failed to parse, which exceeds {}%", .max * 100.0)]
+    Rate { errors: u64, lines: u64, max: f64 },
+}
+
+/// Shared counter of parsed lines and parse errors, which decides whether a parse error can be
+/// skipped or must abort the whole run.
+pub(crate) struct ErrorBudget {
+    skip_parse_errors: bool,
+    max_errors: Option<u64>,
+    max_error_rate: Option<f64>,
+    lines: AtomicU64,
+    errors: AtomicU64,
+    /// Sources whose lines are counted already, if they are tracked at all
+    sources: Option<Mutex<HashSet<String>>>,
+}
+
+impl ErrorBudget {
+    pub(crate) fn new(
+        skip_parse_errors: bool,
+        max_errors: Option<u64>,
+        max_error_rate: Option<f64>,
+    ) -> Self {
+        Self {
+            skip_parse_errors,
+            max_errors,
+            max_error_rate,
+            lines: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            sources: None,
+        }
+    }
+
+    /// Remembers every source read, for queries which may scan the same file more than once,
+    /// e.g. for a self-join, while its lines and errors must be counted only once.
+    pub(crate) fn track_sources(&mut self) {
+        self.sources = Some(Mutex::new(HashSet::new()));
+    }
+
+    /// Whether `source` is read for the first time. Always true unless sources are tracked.
+    pub(crate) fn first_read(&self, source: &str) -> bool {
+        match &self.sources {
+            Some(sources) => sources.lock().unwrap().insert(source.to_owned()),
+            None => true,
+        }
+    }
+
+    /// Whether parse errors are skipped at all. Setting any of the thresholds implies skipping.
+    pub(crate) fn tolerates_errors(&self) -> bool {
+        self.skip_parse_errors || self.max_errors.is_some() || self.max_error_rate.is_some()
+    }
+
+    pub(crate) fn add_lines(&self, lines: u64) {
+        self.lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    /// Records a parse error. Returns an error if the line must not be skipped.
+    pub(crate) fn add_error(&self, err: &ParseLogError) -> Result<()> {
+        if !self.tolerates_errors() {
+            return Err(err.clone().into());
+        }
+        let errors = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.max_errors {
+            Some(max) if errors > max => Err(ErrorBudgetExceeded::Count { max }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Checks the error rate over every line read so far. Should be called once all the inputs
+    /// are consumed, since the rate over a part of them means little, so --max-error-rate never
+    /// stops a run early.
+    pub(crate) fn check_rate(&self) -> Result<()> {
+        let (errors, lines) = (self.errors(), self.lines.load(Ordering::Relaxed));
+        match self.max_error_rate {
+            Some(max) if lines > 0 && errors as f64 / lines as f64 > max => {
+                Err(ErrorBudgetExceeded::Rate { errors, lines, max }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parses an error rate either as a fraction (`0.001`) or as a percentage (`0.1%`).
+pub(crate) fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate = match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|p| p / 100.0),
+        None => s.parse::<f64>(),
+    }
+    .map_err(|e| e.to_string())?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("{s} is not between 0% and 100%"));
+    }
+    Ok(rate)
+}
+
+#[test]
+fn test_error_budget() {
+    use crate::classic_lb::LogParser;
+    use crate::parse::LBLogParser;
+
+    let Err(err) = LogParser::new().parse(b"invalid") else {
+        unreachable!()
+    };
+
+    let budget = ErrorBudget::new(false, None, None);
+    assert!(budget.add_error(&err).is_err());
+
+    let budget = ErrorBudget::new(false, Some(2), None);
+    assert!(budget.add_error(&err).is_ok());
+    assert!(budget.add_error(&err).is_ok());
+    assert!(budget.add_error(&err).is_err());
+
+    let budget = ErrorBudget::new(false, None, Some(0.5));
+    budget.add_lines(4);
+    assert!(budget.add_error(&err).is_ok());
+    assert!(budget.add_error(&err).is_ok());
+    assert!(budget.check_rate().is_ok());
+    assert!(budget.add_error(&err).is_ok());
+    assert!(budget.check_rate().is_err());
+
+    let mut budget = ErrorBudget::new(true, None, None);
+    assert!(budget.first_read("a.log"));
+    assert!(budget.first_read("a.log"));
+    budget.track_sources();
+    assert!(budget.first_read("a.log"));
+    assert!(!budget.first_read("a.log"));
+
+    assert_eq!(parse_rate("0.1%"), Ok(0.001));
+    assert_eq!(parse_rate("0.25"), Ok(0.25));
+    assert!(parse_rate("120%").is_err());
+    assert!(parse_rate("abc").is_err());
+}
@@ -3,7 +3,8 @@ use std::cell::RefCell;
 use regex::bytes::{CaptureLocations, Regex};
 use serde::Serialize;
 
-use crate::parse::{LBLogParser, ParseLogError, bytes_ser};
+use crate::diagnose::Diagnoser;
+use crate::parse::{LBLogParser, LogFields, ParseLogError, bytes_ser};
 
 #[derive(Serialize)]
 pub struct Log<'a> {
@@ -45,6 +46,34 @@ pub struct Log<'a> {
     pub ssl_protocol: &'a [u8],
 }
 
+impl LogFields for Log<'_> {
+    fn fields(&self) -> Vec<Option<&[u8]>> {
+        vec![
+            Some(self.time),
+            Some(self.elb),
+            Some(self.client_ip),
+            Some(self.client_port),
+            Some(self.backend_ip_port),
+            Some(self.request_processing_time),
+            Some(self.backend_processing_time),
+            Some(self.response_processing_time),
+            Some(self.elb_status_code),
+            Some(self.backend_status_code),
+            Some(self.received_bytes),
+            Some(self.sent_bytes),
+            Some(self.http_method),
+            Some(self.url),
+            Some(self.http_version),
+            Some(self.user_agent),
+            Some(self.ssl_cipher),
+            Some(self.ssl_protocol),
+        ]
+    }
+}
+
+/// Diagnoser of lines which failed to parse, shared by every parser
+static DIAGNOSER: Diagnoser = Diagnoser::new(LogParser::REGEX, LogParser::FIELDS);
+
 pub struct LogParser {
     regex: Regex,
     locs: RefCell<CaptureLocations>,
@@ -86,12 +115,13 @@ impl LBLogParser for LogParser {
         "
             (-|[A-Z]+)                                      # http method
             \x20
-            ((?:[^\n\\"]|\\"|\\\\|\\x[0-9a-f]{8})*)         # URL
+            ((?:(?-u:[^\n\\"])|\\"|\\\\|\\x[0-9a-f]{8})*)   # URL
+                                                            # MEMO: (?-u:...) accepts raw bytes which are not valid UTF-8, see `--invalid-utf8`
             \x20
             (-\x20|HTTP/[0-9.]+)                            # http version
         "
         \x20
-        "((?:[^\n\\"]|\\"|\\\\|\\x[0-9a-f]{8})*)"           # user agent
+        "((?:(?-u:[^\n\\"])|\\"|\\\\|\\x[0-9a-f]{8})*)"     # user agent
         \x20
         ([0-9A-Z-]+)                                        # ssl cipher
         \x20
@@ -99,6 +129,32 @@ impl LBLogParser for LogParser {
         \x0A?
         $
     "#;
+    const FIELDS: &'static [&'static str] = &[
+        "time",
+        "elb",
+        "client_ip",
+        "client_port",
+        "backend_ip_port",
+        "request_processing_time",
+        "backend_processing_time",
+        "response_processing_time",
+        "elb_status_code",
+        "backend_status_code",
+        "received_bytes",
+        "sent_bytes",
+        "http_method",
+        "url",
+        "http_version",
+        "user_agent",
+        "ssl_cipher",
+        "ssl_protocol",
+    ];
+    const TEMPLATE: &'static str = concat!(
+        r#"{time} {elb} {client_ip}:{client_port} {backend_ip_port} "#,
+        r#"{request_processing_time} {backend_processing_time} {response_processing_time} "#,
+        r#"{elb_status_code} {backend_status_code} {received_bytes} {sent_bytes} "#,
+        r#""{http_method} {url} {http_version}" "{user_agent}" {ssl_cipher} {ssl_protocol}"#,
+    );
 
     fn new() -> Self {
         let regex = Regex::new(Self::REGEX).unwrap();
@@ -110,7 +166,7 @@ impl LBLogParser for LogParser {
         let mut locs = self.locs.borrow_mut();
         self.regex
             .captures_read(&mut locs, log)
-            .ok_or_else(|| ParseLogError::InvalidLogFormat(log.to_owned()))?;
+            .ok_or_else(|| DIAGNOSER.error(log))?;
 
         let s = |i| {
             let (start, end) = locs.get(i).unwrap();
@@ -143,11 +199,17 @@ impl LBLogParser for LogParser {
 #[test]
 fn test_log_parser() -> Result<(), ParseLogError> {
     let parser = LogParser::new();
-    let t = |input, expected| -> Result<(), ParseLogError> {
-        assert_eq!(
-            serde_json::to_string(&mut parser.parse(input)?).unwrap(),
-            expected
-        );
+    // Parses `input`, and renders it back as is
+    let t = |input: &[u8], expected| -> Result<(), ParseLogError> {
+        let log = parser.parse(input)?;
+        assert_eq!(serde_json::to_string(&log).unwrap(), expected);
+        let raw = crate::format::FormatConfig {
+            format: crate::format::Format::Raw,
+            ..Default::default()
+        };
+        let rendered = crate::format::render_log::<LogParser>(&log, &raw).unwrap();
+        let line = input.strip_suffix(b"\n").unwrap_or(input);
+        assert_eq!(rendered, String::from_utf8_lossy(line));
         Ok(())
     };
 
@@ -180,10 +242,14 @@ fn test_log_parser() -> Result<(), ParseLogError> {
     //
     // Error cases
     //
-    let Err(ParseLogError::InvalidLogFormat(_)) = parser.parse(b"2015-03-27T07:06:41.177907Z")
-    else {
+    let Err(err) = parser.parse(b"2015-03-27T07:06:41.177907Z") else {
         panic!("Expected error for incomplete log line");
     };
+    let diagnostic = err.diagnostic();
+    assert_eq!(
+        diagnostic.to_string(),
+        "field `elb` (2): expected `[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?`, found end of line"
+    );
 
     Ok(())
 }
@@ -0,0 +1,268 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+use regex::bytes::Regex;
+use regex_automata::Input;
+use regex_automata::dfa::{Automaton, dense};
+use regex_automata::util::syntax;
+use regex_syntax::ast::{self, Ast, Concat, print::Printer};
+
+use crate::parse::ParseLogError;
+
+/// Describes why a log line did not match the regex of its parser. This is a part of the error
+/// reported on stderr, where the failed position is highlighted only if stderr is a terminal.
+#[derive(Clone, Debug)]
+pub(crate) struct Diagnostic {
+    /// Byte offset of the first byte which cannot be matched. Equal to the length of the line if
+    /// the line ended too early.
+    pub(crate) position: Option<usize>,
+    /// Capture group index (starting from 1) and name of the field which failed to parse. `None`
+    /// if every field was parsed but the line has unexpected trailing input.
+    pub(crate) field: Option<(usize, &'static str)>,
+    /// Pattern which the field must match
+    pub(crate) expected: String,
+    /// Actual content of the field
+    pub(crate) found: Vec<u8>,
+}
+
+impl Diagnostic {
+    /// Describes where the line failed to parse without showing any of its content
+    pub(crate) fn location(&self) -> String {
+        match self.field {
+            Some((idx, name)) => format!("field `{name}` ({idx}): expected `{}`", self.expected),
+            None => "unexpected trailing input".to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let found = String::from_utf8_lossy(&self.found);
+        let found = found.trim_end();
+        match self.field {
+            Some((idx, name)) if found.is_empty() => write!(
+                f,
+                "field `{name}` ({idx}): expected `{}`, found end of line",
+                self.expected
+            ),
+            Some((idx, name)) => write!(
+                f,
+                "field `{name}` ({idx}): expected `{}`, found `{found}`",
+                self.expected
+            ),
+            None => write!(f, "unexpected trailing input `{found}`"),
+        }
+    }
+}
+
+/// Finds which field of a log line failed to parse.
+///
+/// Everything is compiled lazily on the first diagnosis, since most of the inputs are expected to
+/// be parsed without any errors. Each parser keeps its diagnoser in a `static`, so that the
+/// compiled DFA is shared by every thread and file.
+#[derive(Debug)]
+pub(crate) struct Diagnoser {
+    regex: &'static str,
+    fields: &'static [&'static str],
+    compiled: OnceLock<Compiled>,
+}
+
+#[derive(Debug)]
+struct Compiled {
+    dfa: dense::DFA<Vec<u32>>,
+    fields: Vec<Field>,
+}
+
+#[derive(Debug)]
+struct Field {
+    /// Matches every field before this one, including the separator right before this field
+    before: Regex,
+    /// Matches every field up to and including this one
+    through: Regex,
+    /// Matches every field up to and including this one, along with its terminator. A field
+    /// which matches only partially, e.g. `[a-z]+` against `aB`, fails this.
+    terminated: Option<Regex>,
+    /// Literal which terminates this field, if any
+    terminator: Option<u8>,
+    expected: String,
+}
+
+impl Diagnoser {
+    pub(crate) const fn new(regex: &'static str, fields: &'static [&'static str]) -> Self {
+        Self {
+            regex,
+            fields,
+            compiled: OnceLock::new(),
+        }
+    }
+
+    /// Error of a line which failed to parse, which is diagnosed only once it is reported.
+    pub(crate) fn error(&'static self, log: &[u8]) -> ParseLogError {
+        ParseLogError::InvalidLogFormat(log.to_owned(), self)
+    }
+
+    pub(crate) fn diagnose(&self, log: &[u8]) -> Diagnostic {
+        let compiled = self.compiled.get_or_init(|| self.compile());
+        let position = compiled.find_failed_position(log);
+
+        let mut end_of_previous = 0;
+        for (idx, field) in compiled.fields.iter().enumerate() {
+            // The line may end right after this field, in which case the next one is missing
+            let through = field.through.find(log);
+            let terminated = match &field.terminated {
+                Some(terminated) => terminated.find(log).map(|m| m.end()),
+                None => through.map(|m| m.end()),
+            };
+            let ended = through.filter(|m| matches!(&log[m.end()..], b"" | b"\n"));
+            if let Some(end) = terminated.or(ended.map(|m| m.end())) {
+                end_of_previous = end;
+                continue;
+            }
+
+            let start = field.before.find(log).map_or(end_of_previous, |m| m.end());
+            let rest = &log[start..];
+            let end = rest
+                .iter()
+                .position(|&b| Some(b) == field.terminator || b == b'\n')
+                .unwrap_or(rest.len());
+            return Diagnostic {
+                position,
+                field: Some((idx + 1, self.fields[idx])),
+                expected: field.expected.clone(),
+                found: rest[..end].to_owned(),
+            };
+        }
+
+        Diagnostic {
+            position,
+            field: None,
+            expected: String::new(),
+            found: log[end_of_previous..].to_owned(),
+        }
+    }
+
+    fn compile(&self) -> Compiled {
+        let syntax = syntax::Config::new().utf8(false);
+        let dfa = dense::Builder::new()
+            .syntax(syntax)
+            .build(self.regex)
+            .unwrap();
+
+        let ast = ast::parse::Parser::new().parse(self.regex).unwrap();
+        let Ast::Concat(concat) = &ast else {
+            unreachable!("regex of a parser must be a concatenation of fields")
+        };
+        let prefix = |len: usize| {
+            let mut pattern = String::new();
+            Printer::new()
+                .print(
+                    &Ast::concat(Concat {
+                        span: concat.span,
+                        asts: concat.asts[..len].to_vec(),
+                    }),
+                    &mut pattern,
+                )
+                .unwrap();
+            Regex::new(&pattern).unwrap()
+        };
+
+        let fields = (1..=self.fields.len())
+            .map(|capture| {
+                let (pos, group) = concat
+                    .asts
+                    .iter()
+                    .enumerate()
+                    .find_map(|(pos, ast)| find_capture(ast, capture).map(|group| (pos, group)))
+                    .unwrap();
+                let mut expected = String::new();
+                Printer::new().print(&group.ast, &mut expected).unwrap();
+                let terminator = match concat.asts.get(pos + 1) {
+                    Some(Ast::Literal(literal)) => u8::try_from(literal.c).ok(),
+                    _ => None,
+                };
+                Field {
+                    before: prefix(pos),
+                    through: prefix(pos + 1),
+                    terminated: terminator.map(|_| prefix(pos + 2)),
+                    terminator,
+                    expected,
+                }
+            })
+            .collect();
+
+        Compiled { dfa, fields }
+    }
+}
+
+impl Compiled {
+    fn find_failed_position(&self, log: &[u8]) -> Option<usize> {
+        let dfa = &self.dfa;
+        let mut s = dfa.start_state_forward(&Input::new(log)).unwrap();
+
+        for (idx, &byte) in log.iter().enumerate() {
+            s = dfa.next_state(s, byte);
+            if dfa.is_dead_state(s) {
+                return Some(idx);
+            }
+        }
+        s = dfa.next_eoi_state(s);
+        if dfa.is_dead_state(s) {
+            return Some(log.len());
+        }
+
+        None
+    }
+}
+
+/// Finds the capture group with given index in the AST.
+fn find_capture(ast: &Ast, capture: usize) -> Option<&ast::Group> {
+    match ast {
+        Ast::Group(group) if group.capture_index() == Some(capture as u32) => Some(group),
+        Ast::Group(group) => find_capture(&group.ast, capture),
+        Ast::Repetition(repetition) => find_capture(&repetition.ast, capture),
+        Ast::Concat(concat) => concat
+            .asts
+            .iter()
+            .find_map(|ast| find_capture(ast, capture)),
+        Ast::Alternation(alternation) => alternation
+            .asts
+            .iter()
+            .find_map(|ast| find_capture(ast, capture)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_diagnose() {
+    static DIAGNOSER: Diagnoser = Diagnoser::new(
+        r#"(?x) ^ ([0-9]+) \x20 "([a-z]+)" \x20 (-|[A-Z]+) $"#,
+        &["number", "word", "upper"],
+    );
+    let diagnose = |log: &[u8]| DIAGNOSER.diagnose(log);
+
+    let diagnostic = diagnose(b"x12 \"ab\" -");
+    assert_eq!(diagnostic.field, Some((1, "number")));
+    assert_eq!(diagnostic.position, Some(0));
+    assert_eq!(diagnostic.found, b"x12");
+
+    let diagnostic = diagnose(b"12 \"aB\" -");
+    assert_eq!(diagnostic.field, Some((2, "word")));
+    assert_eq!(diagnostic.position, Some(5));
+    assert_eq!(diagnostic.found, b"aB");
+    assert_eq!(
+        diagnostic.to_string(),
+        "field `word` (2): expected `[a-z]+`, found `aB`"
+    );
+
+    let diagnostic = diagnose(b"12 \"ab\"");
+    assert_eq!(diagnostic.field, Some((3, "upper")));
+    assert_eq!(diagnostic.position, Some(7));
+    assert!(diagnostic.to_string().ends_with("found end of line"));
+
+    let diagnostic = diagnose(b"12 \"ab\" AB cd");
+    assert_eq!(diagnostic.field, None);
+    assert_eq!(diagnostic.position, Some(10));
+    assert_eq!(diagnostic.to_string(), "unexpected trailing input ` cd`");
+
+    assert_eq!(diagnose(b"12 \"ab\" AB").position, None);
+}
@@ -0,0 +1,368 @@
+mod anonymize;
+pub(crate) mod arn;
+mod cidr;
+mod geoip;
+pub(crate) mod lists;
+mod reason;
+mod route;
+mod time;
+pub(crate) mod trace;
+pub(crate) mod url;
+mod user_agent;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use clap::{Args, ValueEnum, ValueHint};
+use jiff::tz::TimeZone;
+
+use self::anonymize::{Anonymizer, Policy};
+use self::cidr::CidrTags;
+use self::geoip::{Geo, GeoIp};
+use self::reason::Explainer;
+use self::route::Routes;
+pub(crate) use self::time::TIME_FIELDS;
+use self::time::{TimeFormat, parse_time_format, parse_tz};
+use self::user_agent::{UserAgent, UserAgentParser};
+use crate::format::Format;
+use crate::record::Record;
+
+/// Options for the derived fields added to each record
+#[derive(Args, Clone, Default, Debug)]
+#[command(next_help_heading = "Enrichment")]
+pub(crate) struct EnrichConfig {
+    /// Split url into url_scheme, url_host, url_port, url_path and url_query.
+    #[arg(long)]
+    split_url: bool,
+
+    /// Also add url_query_params, a map from each query key to the list of its values. Implies
+    /// --split-url.
+    #[arg(long)]
+    url_query_params: bool,
+
+    /// Percent-decode url_path and url_query_params.
+    #[arg(long)]
+    percent_decode: bool,
+
+    /// Add route, the path of url with IDs, UUIDs and hashes replaced by placeholders, e.g.
+    /// "/users/{id}".
+    #[arg(long)]
+    route: bool,
+
+    /// Route patterns to try before the built-in placeholders, one per line, e.g.
+    /// "/users/{name}/orders/*". Implies --route.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    routes: Option<PathBuf>,
+
+    /// Parse user_agent into ua_family, ua_version, ua_os, ua_os_version, ua_device and
+    /// ua_bot_class, using the bundled user agent database.
+    #[arg(long)]
+    user_agent: bool,
+
+    /// User agent database to use instead of the bundled one, e.g. regexes.yaml of uap-core.
+    /// Implies --user-agent.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    user_agent_db: Option<PathBuf>,
+
+    /// MaxMind DB of locations, e.g. GeoLite2-City.mmdb. Adds client_geo_country_code,
+    /// client_geo_country, client_geo_region, client_geo_city, client_geo_latitude and
+    /// client_geo_longitude.
+    #[arg(long, value_name = "MMDB", value_hint = ValueHint::FilePath)]
+    geoip: Option<PathBuf>,
+
+    /// MaxMind DB of autonomous systems, e.g. GeoLite2-ASN.mmdb. Adds client_asn and
+    /// client_asn_org.
+    #[arg(long, value_name = "MMDB", value_hint = ValueHint::FilePath)]
+    asn: Option<PathBuf>,
+
+    /// CSV of "cidr,label" rows, e.g. "10.0.0.0/8,internal". Adds client_tag, target_tag (or
+    /// backend_tag) and target_tags, the labels of the longest matching CIDR blocks.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    tag_cidrs: Option<PathBuf>,
+
+    /// Split target_group_arn and chosen_cert_arn into partition, service, region, account and
+    /// resource, target groups further into target_group_name and target_group_id, and elb into
+    /// elb_type, elb_name and elb_id.
+    #[arg(long)]
+    split_arn: bool,
+
+    /// Split trace_id into trace_root, trace_self, trace_parent, trace_sampled, trace_time (the
+    /// epoch seconds in the root ID), trace_w3c_id and traceparent (in the W3C format).
+    #[arg(long)]
+    split_trace_id: bool,
+
+    /// Emit actions_executed, target_ip_port_list and target_status_code_list as arrays, and add
+    /// targets, an array of {ip, port, status} zipping the latter two. Actions which are not
+    /// documented are "unknown".
+    #[arg(long)]
+    expand_lists: bool,
+
+    /// Add error_reason_description and classification_reason_description, the documented
+    /// meanings of the codes. Codes which are not documented are "unknown", and how many times
+    /// each of them appeared is reported at the end of the run.
+    #[arg(long)]
+    explain: bool,
+
+    /// Render time and request_creation_time in this time zone, e.g. "Asia/Seoul".
+    #[arg(long, value_name = "ZONE", value_parser = parse_tz)]
+    tz: Option<TimeZone>,
+
+    /// Render time and request_creation_time as epoch, epoch_ms, epoch_us, rfc3339, or a strftime
+    /// pattern, e.g. "%Y-%m-%d %H:%M:%S". Defaults to rfc3339 if --tz is given. Only rfc3339 is
+    /// allowed with --format ecs and otlp-json, and timestamps of --format raw and combined are
+    /// left as they are.
+    #[arg(long, value_name = "FORMAT", value_parser = parse_time_format)]
+    time_format: Option<TimeFormat>,
+
+    /// Add queue_time, the seconds from request_creation_time to time.
+    #[arg(long)]
+    queue_time: bool,
+
+    /// Anonymize records before anything else: truncate client_ip to /24 (/48 for IPv6), remove
+    /// query strings from url and redirect_url, drop user_agent and hash trace_id. Lines which fail
+    /// to parse are reported without their content.
+    #[arg(long)]
+    anonymize: bool,
+
+    /// Anonymization policy to use instead of the default one, in YAML. Implies --anonymize.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    anonymize_policy: Option<PathBuf>,
+}
+
+impl EnrichConfig {
+    /// Fails if timestamps would be rendered in a way which the output format cannot read back.
+    /// ECS and OTLP need RFC 3339 timestamps, while log line formats ignore --time-format.
+    pub(crate) fn check_format(&self, format: Format) -> Result<()> {
+        match &self.time_format {
+            Some(time_format)
+                if *time_format != TimeFormat::Rfc3339
+                    && matches!(format, Format::Ecs | Format::OtlpJson) =>
+            {
+                let format = format.to_possible_value().unwrap();
+                bail!(
+                    "--format {} requires --time-format rfc3339",
+                    format.get_name()
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Time zone of time and request_creation_time if they are rendered in RFC 3339, whose text
+    /// sorts in the order of time, e.g. "2024-05-28T22:05:00.000000+09:00".
+    pub(crate) fn rfc3339_zone(&self) -> Option<TimeZone> {
+        match &self.time_format {
+            None | Some(TimeFormat::Rfc3339) => Some(self.tz.clone().unwrap_or(TimeZone::UTC)),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Adds derived fields to records
+pub(crate) struct Enricher {
+    url: Option<url::Options>,
+    routes: Option<Routes>,
+    user_agents: Option<UserAgentParser>,
+    geoip: Option<GeoIp>,
+    cidr_tags: Option<CidrTags>,
+    split_arn: bool,
+    split_trace_id: bool,
+    expand_lists: bool,
+    explainer: Option<Explainer>,
+    time: Option<time::Options>,
+    anonymizer: Option<Anonymizer>,
+}
+
+/// Caches of an `Enricher`, which are kept per thread
+#[derive(Default)]
+pub(crate) struct Caches {
+    user_agents: Cache<UserAgent>,
+    geoip: Cache<Geo>,
+}
+
+/// Memoizes derived values by their input. Logs repeat the same values a lot, so this just
+/// starts over once it gets too large instead of tracking which entries are recently used.
+pub(crate) struct Cache<V> {
+    map: HashMap<String, V>,
+}
+
+impl<V> Default for Cache<V> {
+    fn default() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Cache<V> {
+    const CAPACITY: usize = 1 << 16;
+
+    pub(crate) fn get_or_insert_with(&mut self, key: &str, f: impl FnOnce() -> V) -> &V {
+        if !self.map.contains_key(key) {
+            if self.map.len() >= Self::CAPACITY {
+                self.map.clear();
+            }
+            self.map.insert(key.to_owned(), f());
+        }
+        &self.map[key]
+    }
+}
+
+impl Enricher {
+    pub(crate) fn new(config: &EnrichConfig) -> Result<Self> {
+        let url = (config.split_url || config.url_query_params).then_some(url::Options {
+            query_params: config.url_query_params,
+            percent_decode: config.percent_decode,
+        });
+        let routes = match &config.routes {
+            Some(path) => Some(Routes::load(path)?),
+            None => config.route.then(Routes::default),
+        };
+        let user_agents = match &config.user_agent_db {
+            Some(path) => Some(UserAgentParser::load(path)?),
+            None => config.user_agent.then(UserAgentParser::bundled),
+        };
+        let geoip = if config.geoip.is_some() || config.asn.is_some() {
+            Some(GeoIp::open(config.geoip.as_deref(), config.asn.as_deref())?)
+        } else {
+            None
+        };
+        let time =
+            (config.tz.is_some() || config.time_format.is_some() || config.queue_time).then(|| {
+                time::Options {
+                    tz: config.tz.clone().unwrap_or(TimeZone::UTC),
+                    format: config
+                        .time_format
+                        .clone()
+                        .or_else(|| config.tz.is_some().then_some(TimeFormat::Rfc3339)),
+                    queue_time: config.queue_time,
+                }
+            });
+        let anonymizer = match &config.anonymize_policy {
+            Some(path) => Some(Anonymizer::new(Policy::load(path)?)?),
+            None => config
+                .anonymize
+                .then(|| Anonymizer::new(Policy::default()))
+                .transpose()?,
+        };
+        let cidr_tags = config
+            .tag_cidrs
+            .as_deref()
+            .map(CidrTags::load)
+            .transpose()?;
+        Ok(Self {
+            url,
+            routes,
+            user_agents,
+            geoip,
+            cidr_tags,
+            split_arn: config.split_arn,
+            split_trace_id: config.split_trace_id,
+            expand_lists: config.expand_lists,
+            explainer: config.explain.then(Explainer::default),
+            time,
+            anonymizer,
+        })
+    }
+
+    /// Whether records are anonymized, in which case nothing else may show the original line
+    pub(crate) fn anonymizes(&self) -> bool {
+        self.anonymizer.is_some()
+    }
+
+    /// Whether no derived field will be added at all
+    pub(crate) fn is_empty(&self) -> bool {
+        self.url.is_none()
+            && self.routes.is_none()
+            && self.user_agents.is_none()
+            && self.geoip.is_none()
+            && self.cidr_tags.is_none()
+            && !self.split_arn
+            && !self.split_trace_id
+            && !self.expand_lists
+            && self.explainer.is_none()
+            && self.time.is_none()
+            && self.anonymizer.is_none()
+    }
+
+    pub(crate) fn enrich(&self, record: &mut Record, caches: &mut Caches) {
+        // Derived fields must not leak what is anonymized
+        if let Some(anonymizer) = &self.anonymizer {
+            anonymizer.enrich(record);
+        }
+        if let Some(options) = &self.url {
+            url::enrich(record, options);
+        }
+        if let Some(routes) = &self.routes {
+            routes.enrich(record);
+        }
+        if let Some(user_agents) = &self.user_agents {
+            user_agents.enrich(record, &mut caches.user_agents);
+        }
+        if let Some(geoip) = &self.geoip {
+            geoip.enrich(record, &mut caches.geoip);
+        }
+        if let Some(cidr_tags) = &self.cidr_tags {
+            cidr_tags.enrich(record);
+        }
+        if self.split_arn {
+            arn::enrich(record);
+        }
+        if self.split_trace_id {
+            trace::enrich(record);
+        }
+        if self.expand_lists {
+            lists::enrich(record);
+        }
+        if let Some(explainer) = &self.explainer {
+            explainer.enrich(record);
+        }
+        if let Some(options) = &self.time {
+            time::enrich(record, options);
+        }
+    }
+
+    /// Reports what was noticed while enriching records, at the end of a run
+    pub(crate) fn summary(&self) {
+        if let Some(explainer) = &self.explainer {
+            explainer.summary();
+        }
+    }
+}
+
+#[test]
+fn test_time_options() {
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        enrich: EnrichConfig,
+    }
+    let config = |args: &[&str]| {
+        Cli::try_parse_from(["elb-log-parser"].iter().chain(args))
+            .unwrap()
+            .enrich
+    };
+
+    // --tz renders timestamps in rfc3339, even in UTC
+    let enricher = Enricher::new(&config(&["--tz", "UTC"])).unwrap();
+    let mut record = Record::default();
+    record.insert("time", "2020-01-11T01:11:10.111111Z");
+    enricher.enrich(&mut record, &mut Caches::default());
+    assert_eq!(
+        record.get_str("time"),
+        Some("2020-01-11T01:11:10.111111+00:00")
+    );
+
+    let epoch = config(&["--time-format", "epoch"]);
+    assert!(epoch.check_format(Format::Json).is_ok());
+    assert!(epoch.check_format(Format::Ecs).is_err());
+    assert!(epoch.check_format(Format::Raw).is_ok());
+    assert!(
+        config(&["--tz", "Asia/Seoul"])
+            .check_format(Format::OtlpJson)
+            .is_ok()
+    );
+}
@@ -0,0 +1,212 @@
+use std::fs::read_to_string;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::record::Record;
+
+/// Fields holding URLs whose query strings are redacted
+const URL_FIELDS: &[&str] = &["url", "redirect_url"];
+
+const REDACTED: &str = "REDACTED";
+
+/// What to anonymize, and how. Every field of a policy file is optional, and defaults to the
+/// policy of `--anonymize`.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Policy {
+    client_ip: IpPolicy,
+    /// Prefix lengths which IPv4 and IPv6 addresses are truncated to
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    query: QueryPolicy,
+    /// Keys whose values are redacted when the query string is kept
+    query_keys: Vec<String>,
+    drop_user_agent: bool,
+    hash_trace_id: bool,
+    /// HMAC key for pseudonyms. A random key is used if not given, so pseudonyms are consistent
+    /// only within a run.
+    key: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum IpPolicy {
+    Keep,
+    Truncate,
+    Hmac,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum QueryPolicy {
+    Keep,
+    Remove,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            client_ip: IpPolicy::Truncate,
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+            query: QueryPolicy::Remove,
+            query_keys: vec![],
+            drop_user_agent: true,
+            hash_trace_id: true,
+            key: None,
+        }
+    }
+}
+
+impl Policy {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let yaml = read_to_string(path)
+            .with_context(|| format!("Failed to read anonymization policy {}", path.display()))?;
+        serde_yaml::from_str(&yaml)
+            .with_context(|| format!("Invalid anonymization policy {}", path.display()))
+    }
+}
+
+pub(crate) struct Anonymizer {
+    policy: Policy,
+    hmac: Hmac<Sha256>,
+}
+
+impl Anonymizer {
+    pub(crate) fn new(policy: Policy) -> Result<Self> {
+        let key = match &policy.key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => random_key()?,
+        };
+        let hmac = Hmac::new_from_slice(&key).expect("HMAC accepts keys of any length");
+        Ok(Self { policy, hmac })
+    }
+
+    /// Hex digits of the keyed hash of a value, truncated to `len` digits
+    fn pseudonym(&self, value: &str, len: usize) -> String {
+        let mut hmac = self.hmac.clone();
+        hmac.update(value.as_bytes());
+        let digest = hmac.finalize().into_bytes();
+        let mut hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+        hex.truncate(len);
+        hex
+    }
+
+    fn anonymize_ip(&self, ip: &str) -> String {
+        match self.policy.client_ip {
+            IpPolicy::Keep => ip.to_owned(),
+            IpPolicy::Hmac => self.pseudonym(ip, 16),
+            IpPolicy::Truncate => match ip.parse() {
+                Ok(ip) => truncate(ip, self.policy.ipv4_prefix, self.policy.ipv6_prefix),
+                Err(_) => ip.to_owned(),
+            },
+        }
+    }
+
+    fn anonymize_url(&self, url: &str) -> String {
+        let (url, fragment) = match url.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (url, None),
+        };
+        let mut ret = match url.split_once('?') {
+            None => url.to_owned(),
+            Some((path, _)) if self.policy.query == QueryPolicy::Remove => path.to_owned(),
+            Some((path, query)) => format!("{path}?{}", self.redact_keys(query)),
+        };
+        if let Some(fragment) = fragment {
+            ret.push('#');
+            ret.push_str(fragment);
+        }
+        ret
+    }
+
+    fn redact_keys(&self, query: &str) -> String {
+        query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((key, _)) if self.policy.query_keys.iter().any(|k| k == key) => {
+                    format!("{key}={REDACTED}")
+                }
+                _ => pair.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    pub(super) fn enrich(&self, record: &mut Record) {
+        if let Some(ip) = record.get_str("client_ip") {
+            let ip = self.anonymize_ip(ip);
+            record.insert("client_ip", ip);
+        }
+        for &field in URL_FIELDS {
+            if let Some(url) = record.get_str(field) {
+                let url = self.anonymize_url(url);
+                record.insert(field, url);
+            }
+        }
+        if self.policy.drop_user_agent {
+            record.remove("user_agent");
+        }
+        if self.policy.hash_trace_id
+            && let Some(trace_id) = record.get_str("trace_id").filter(|t| *t != "-")
+        {
+            let trace_id = self.pseudonym(trace_id, 32);
+            record.insert("trace_id", trace_id);
+        }
+    }
+}
+
+/// Zeroes the host bits of an address, e.g. `1.2.3.4` into `1.2.3.0` with a prefix of 24
+fn truncate(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> String {
+    let mask = |bits: u32, prefix: u8| u128::MAX.checked_shl(bits - u32::from(prefix).min(bits));
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = mask(32, ipv4_prefix).unwrap_or(0) as u32;
+            Ipv4Addr::from(u32::from(ip) & mask).to_string()
+        }
+        IpAddr::V6(ip) => {
+            let mask = mask(128, ipv6_prefix).unwrap_or(0);
+            Ipv6Addr::from(u128::from(ip) & mask).to_string()
+        }
+    }
+}
+
+/// Key of 256 bits from the random number generator of the OS, which lives only during a run
+fn random_key() -> Result<Vec<u8>> {
+    let mut key = vec![0; 32];
+    getrandom::fill(&mut key).context("Failed to generate a key for --anonymize")?;
+    Ok(key)
+}
+
+#[test]
+fn test_anonymize() {
+    let anonymizer = Anonymizer::new(Policy::default()).unwrap();
+    assert_eq!(anonymizer.anonymize_ip("1.123.123.123"), "1.123.123.0");
+    assert_eq!(anonymizer.anonymize_ip("2001:db8:1:2::3"), "2001:db8:1::");
+    assert_eq!(
+        anonymizer.anonymize_url("https://example.com:443/path?token=secret#top"),
+        "https://example.com:443/path#top"
+    );
+    assert_eq!(anonymizer.anonymize_url("-"), "-");
+
+    let policy: Policy = serde_yaml::from_str(
+        "client_ip: hmac\nquery: keep\nquery_keys: [token, email]\nkey: secret\n",
+    )
+    .unwrap();
+    let anonymizer = Anonymizer::new(policy).unwrap();
+    let pseudonym = anonymizer.anonymize_ip("1.123.123.123");
+    assert_eq!(pseudonym.len(), 16);
+    assert_eq!(anonymizer.anonymize_ip("1.123.123.123"), pseudonym);
+    assert_ne!(anonymizer.anonymize_ip("1.123.123.124"), pseudonym);
+    assert_eq!(
+        anonymizer.anonymize_url("/login?email=a%40b.c&next=/&token="),
+        "/login?email=REDACTED&next=/&token=REDACTED"
+    );
+
+    assert!(serde_yaml::from_str::<Policy>("client_ip: drop").is_err());
+}
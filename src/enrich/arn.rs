@@ -0,0 +1,134 @@
+use serde_json::Value;
+
+use crate::record::Record;
+
+/// ARN fields, and the names of the fields which their components are written to
+const ARN_FIELDS: &[(&str, [&str; 5])] = &[
+    (
+        "target_group_arn",
+        [
+            "target_group_partition",
+            "target_group_service",
+            "target_group_region",
+            "target_group_account",
+            "target_group_resource",
+        ],
+    ),
+    (
+        "chosen_cert_arn",
+        [
+            "chosen_cert_partition",
+            "chosen_cert_service",
+            "chosen_cert_region",
+            "chosen_cert_account",
+            "chosen_cert_resource",
+        ],
+    ),
+];
+
+/// Components of an ARN, e.g.
+/// `arn:aws:elasticloadbalancing:us-east-1:123456789012:targetgroup/my-targets/73e2d6bc24d8a067`
+#[derive(Default, PartialEq, Debug)]
+pub(crate) struct Arn<'a> {
+    pub(crate) partition: &'a str,
+    pub(crate) service: &'a str,
+    pub(crate) region: &'a str,
+    pub(crate) account: &'a str,
+    pub(crate) resource: &'a str,
+}
+
+/// Parses an ARN. Region and account are empty for global resources, such as IAM server
+/// certificates.
+pub(crate) fn parse(arn: &str) -> Option<Arn<'_>> {
+    let mut parts = arn.strip_prefix("arn:")?.splitn(5, ':');
+    Some(Arn {
+        partition: parts.next()?,
+        service: parts.next()?,
+        region: parts.next()?,
+        account: parts.next()?,
+        resource: parts.next()?,
+    })
+}
+
+/// Splits `app/my-alb/50dc6c495c0c9188` into its type, name and ID. Classic LB names have
+/// neither type nor ID.
+pub(crate) fn split_elb(elb: &str) -> [Option<&str>; 3] {
+    let mut parts = elb.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(r#type), Some(name), Some(id)) => [Some(r#type), Some(name), Some(id)],
+        _ => [None, Some(elb), None],
+    }
+}
+
+pub(super) fn enrich(record: &mut Record) {
+    let opt = |s: &str| (!s.is_empty()).then(|| Value::from(s));
+
+    for (field, names) in ARN_FIELDS {
+        let Some(value) = record.get_str(field) else {
+            continue;
+        };
+        let arn = parse(value).unwrap_or_default();
+        let values = [
+            arn.partition,
+            arn.service,
+            arn.region,
+            arn.account,
+            arn.resource,
+        ]
+        .map(opt);
+        // Target groups are further split into the name and the ID
+        let target_group = (*field == "target_group_arn").then(|| {
+            let name_id = arn.resource.strip_prefix("targetgroup/");
+            let (name, id) = name_id.and_then(|s| s.split_once('/')).unzip();
+            (name.and_then(opt), id.and_then(opt))
+        });
+
+        for (name, value) in names.iter().zip(values) {
+            record.insert(name, value);
+        }
+        if let Some((name, id)) = target_group {
+            record.insert("target_group_name", name);
+            record.insert("target_group_id", id);
+        }
+    }
+
+    if let Some(elb) = record.get_str("elb") {
+        let [r#type, name, id] = split_elb(elb).map(|s| s.map(str::to_owned));
+        record.insert("elb_type", r#type);
+        record.insert("elb_name", name);
+        record.insert("elb_id", id);
+    }
+}
+
+#[test]
+fn test_split_arn() {
+    assert_eq!(
+        parse(
+            "arn:aws:elasticloadbalancing:ap-northeast-3:012345678901:targetgroup/myalb/0123456789abcdef"
+        ),
+        Some(Arn {
+            partition: "aws",
+            service: "elasticloadbalancing",
+            region: "ap-northeast-3",
+            account: "012345678901",
+            resource: "targetgroup/myalb/0123456789abcdef",
+        })
+    );
+    assert_eq!(
+        parse("arn:aws:iam::012345678901:server-certificate/certs/my-cert"),
+        Some(Arn {
+            partition: "aws",
+            service: "iam",
+            region: "",
+            account: "012345678901",
+            resource: "server-certificate/certs/my-cert",
+        })
+    );
+    assert_eq!(parse("-"), None);
+
+    assert_eq!(
+        split_elb("app/my-alb/50dc6c495c0c9188"),
+        [Some("app"), Some("my-alb"), Some("50dc6c495c0c9188")]
+    );
+    assert_eq!(split_elb("my-clb"), [None, Some("my-clb"), None]);
+}
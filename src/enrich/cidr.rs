@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+
+use crate::record::Record;
+
+/// Fields holding addresses, and the fields which their labels are written to
+const TAGGED_FIELDS: &[(&str, &str)] = &[
+    ("client_ip", "client_tag"),
+    ("target_ip_port", "target_tag"),
+    ("backend_ip_port", "backend_tag"),
+];
+
+/// Labels addresses by the longest CIDR block which contains them
+#[derive(Default)]
+pub(crate) struct CidrTags {
+    /// Labels of each prefix length, keyed by the masked network address. IPv4 addresses are
+    /// stored as IPv4-mapped IPv6 addresses.
+    prefixes: Vec<(u8, HashMap<u128, String>)>,
+}
+
+impl CidrTags {
+    /// Loads a CSV file of `cidr,label` rows, e.g. `10.0.0.0/8,internal`. An address without a
+    /// prefix length is a single host. Empty lines, lines starting with `#`, and a header row
+    /// are ignored.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let content = read_to_string(path)
+            .with_context(|| format!("Failed to read CIDR tags from {}", path.display()))?;
+        let mut tags = Self::default();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (cidr, label) = line.split_once(',').unwrap_or((line, ""));
+            let (cidr, label) = (cidr.trim(), label.trim().trim_matches('"'));
+            match parse_cidr(cidr) {
+                Some((network, len)) => tags.insert(network, len, label),
+                None if i == 0 => continue,
+                None => {
+                    return Err(anyhow!("Invalid CIDR block: {cidr}"))
+                        .with_context(|| format!("Failed to parse {}:{}", path.display(), i + 1));
+                }
+            }
+        }
+        Ok(tags)
+    }
+
+    fn insert(&mut self, network: u128, len: u8, label: &str) {
+        let idx = match self.prefixes.binary_search_by(|(l, _)| len.cmp(l)) {
+            Ok(idx) => idx,
+            Err(idx) => {
+                self.prefixes.insert(idx, (len, HashMap::new()));
+                idx
+            }
+        };
+        // The first row wins
+        self.prefixes[idx]
+            .1
+            .entry(mask(network, len))
+            .or_insert_with(|| label.to_owned());
+    }
+
+    pub(crate) fn lookup(&self, ip: IpAddr) -> Option<&str> {
+        let ip = to_u128(ip);
+        self.prefixes
+            .iter()
+            .find_map(|(len, networks)| networks.get(&mask(ip, *len)))
+            .map(String::as_str)
+    }
+
+    fn tag(&self, address: &str) -> Value {
+        parse_address(address)
+            .and_then(|ip| self.lookup(ip))
+            .map_or(Value::Null, Value::from)
+    }
+
+    pub(super) fn enrich(&self, record: &mut Record) {
+        for &(field, tag_field) in TAGGED_FIELDS {
+            if let Some(address) = record.get_str(field) {
+                let tag = self.tag(address);
+                record.insert(tag_field, tag);
+            }
+        }
+        if let Some(list) = record.get_str("target_ip_port_list") {
+            let tags: Vec<_> = list
+                .split(' ')
+                .filter(|address| !address.is_empty() && *address != "-")
+                .map(|address| self.tag(address))
+                .collect();
+            record.insert("target_tags", tags);
+        }
+    }
+}
+
+/// Parses `10.0.0.0/8`, `2001:db8::/32` or a single address into a network and a prefix length
+/// over IPv6
+fn parse_cidr(cidr: &str) -> Option<(u128, u8)> {
+    let (ip, len) = match cidr.split_once('/') {
+        Some((ip, len)) => (ip.parse().ok()?, Some(len.parse::<u8>().ok()?)),
+        None => (cidr.parse().ok()?, None),
+    };
+    let len = match (ip, len) {
+        (IpAddr::V4(_), Some(len)) if len <= 32 => len + 96,
+        (IpAddr::V6(_), Some(len)) if len <= 128 => len,
+        (_, Some(_)) => return None,
+        (_, None) => 128,
+    };
+    Some((to_u128(ip), len))
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn mask(ip: u128, len: u8) -> u128 {
+    ip & u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0)
+}
+
+/// Parses an address with or without a port, e.g. `10.0.0.1`, `10.0.0.1:80` or `[::1]:80`
+fn parse_address(address: &str) -> Option<IpAddr> {
+    if let Ok(ip) = address.parse() {
+        return Some(ip);
+    }
+    let host = match address.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        None => address.rsplit_once(':')?.0,
+    };
+    host.parse().ok()
+}
+
+#[test]
+fn test_cidr_tags() {
+    let mut tags = CidrTags::default();
+    for (cidr, label) in [
+        ("10.0.0.0/8", "internal"),
+        ("10.0.1.0/24", "ap-northeast-2a"),
+        ("10.0.1.7", "bastion"),
+        ("10.0.1.0/24", "ignored"),
+        ("2001:db8::/32", "office"),
+        ("0.0.0.0/0", "internet"),
+    ] {
+        let (network, len) = parse_cidr(cidr).unwrap();
+        tags.insert(network, len, label);
+    }
+    let t = |address, expected: Option<&str>| {
+        assert_eq!(tags.tag(address), expected.map_or(Value::Null, Value::from));
+    };
+
+    t("10.0.1.7:80", Some("bastion"));
+    t("10.0.1.100:80", Some("ap-northeast-2a"));
+    t("10.1.2.3", Some("internal"));
+    t("1.123.123.123", Some("internet"));
+    t("[2001:db8::1]:443", Some("office"));
+    t("2001:db9::1", None);
+    t("-", None);
+
+    assert_eq!(parse_cidr("10.0.0.0/33"), None);
+    assert_eq!(parse_cidr("cidr"), None);
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::read;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use maxminddb::{Reader, geoip2};
+use serde_json::Value;
+
+use super::Cache;
+use crate::record::Record;
+
+/// Looks up the location and the autonomous system of client IPs in local MaxMind DB files, e.g.
+/// GeoLite2-City.mmdb and GeoLite2-ASN.mmdb
+pub(crate) struct GeoIp {
+    city: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub(crate) struct Geo {
+    pub(crate) country_code: Option<String>,
+    pub(crate) country: Option<String>,
+    pub(crate) region: Option<String>,
+    pub(crate) city: Option<String>,
+    pub(crate) latitude: Option<f64>,
+    pub(crate) longitude: Option<f64>,
+    pub(crate) asn: Option<u32>,
+    pub(crate) asn_org: Option<String>,
+}
+
+impl GeoIp {
+    pub(crate) fn open(city: Option<&Path>, asn: Option<&Path>) -> Result<Self> {
+        Ok(Self {
+            city: city.map(open).transpose()?,
+            asn: asn.map(open).transpose()?,
+        })
+    }
+
+    pub(crate) fn lookup(&self, ip: IpAddr) -> Geo {
+        let city = self
+            .city
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::City>(ip).ok());
+        let asn = self
+            .asn
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::Asn>(ip).ok());
+        Geo::new(city, asn)
+    }
+
+    pub(super) fn enrich(&self, record: &mut Record, cache: &mut Cache<Geo>) {
+        let Some(client_ip) = record.get_str("client_ip") else {
+            return;
+        };
+        let geo = cache.get_or_insert_with(client_ip, || match client_ip.parse() {
+            Ok(ip) => self.lookup(ip),
+            Err(_) => Geo::default(),
+        });
+
+        let str = |s: &Option<String>| s.as_deref().map_or(Value::Null, Value::from);
+        let num = |n: Option<f64>| n.map_or(Value::Null, Value::from);
+        let mut fields = vec![];
+        if self.city.is_some() {
+            fields.extend([
+                ("client_geo_country_code", str(&geo.country_code)),
+                ("client_geo_country", str(&geo.country)),
+                ("client_geo_region", str(&geo.region)),
+                ("client_geo_city", str(&geo.city)),
+                ("client_geo_latitude", num(geo.latitude)),
+                ("client_geo_longitude", num(geo.longitude)),
+            ]);
+        }
+        if self.asn.is_some() {
+            fields.extend([
+                ("client_asn", geo.asn.map_or(Value::Null, Value::from)),
+                ("client_asn_org", str(&geo.asn_org)),
+            ]);
+        }
+        for (name, value) in fields {
+            record.insert(name, value);
+        }
+    }
+}
+
+impl Geo {
+    fn new(city: Option<geoip2::City>, asn: Option<geoip2::Asn>) -> Self {
+        let mut ret = Self::default();
+        if let Some(city) = city {
+            if let Some(country) = city.country {
+                ret.country_code = country.iso_code.map(str::to_owned);
+                ret.country = english(country.names);
+            }
+            ret.region = city
+                .subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| english(subdivision.names));
+            ret.city = city.city.and_then(|city| english(city.names));
+            if let Some(location) = city.location {
+                ret.latitude = location.latitude;
+                ret.longitude = location.longitude;
+            }
+        }
+        if let Some(asn) = asn {
+            ret.asn = asn.autonomous_system_number;
+            ret.asn_org = asn.autonomous_system_organization.map(str::to_owned);
+        }
+        ret
+    }
+}
+
+fn open(path: &Path) -> Result<Reader<Vec<u8>>> {
+    let context = || format!("Failed to open MaxMind DB {}", path.display());
+    let buf = read(path).with_context(context)?;
+    Reader::from_source(buf).with_context(context)
+}
+
+fn english(names: Option<BTreeMap<&str, &str>>) -> Option<String> {
+    names?.get("en").map(|&name| name.to_owned())
+}
+
+#[test]
+fn test_geo() {
+    let city = serde_json::from_str(
+        r#"{
+            "city": {"geoname_id": 1835848, "names": {"en": "Seoul", "ko": "서울"}},
+            "country": {"iso_code": "KR", "names": {"en": "South Korea"}},
+            "location": {"latitude": 37.5112, "longitude": 126.9741},
+            "subdivisions": [{"iso_code": "11", "names": {"en": "Seoul"}}]
+        }"#,
+    )
+    .unwrap();
+    let asn = serde_json::from_str(
+        r#"{"autonomous_system_number": 16509, "autonomous_system_organization": "AMAZON-02"}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        Geo::new(Some(city), Some(asn)),
+        Geo {
+            country_code: Some("KR".to_owned()),
+            country: Some("South Korea".to_owned()),
+            region: Some("Seoul".to_owned()),
+            city: Some("Seoul".to_owned()),
+            latitude: Some(37.5112),
+            longitude: Some(126.9741),
+            asn: Some(16509),
+            asn_org: Some("AMAZON-02".to_owned()),
+        }
+    );
+    assert_eq!(Geo::new(None, None), Geo::default());
+}
@@ -0,0 +1,121 @@
+use serde_json::{Value, json};
+
+use crate::record::Record;
+
+/// Values of actions_executed, documented at
+/// https://docs.aws.amazon.com/elasticloadbalancing/latest/application/load-balancer-access-logs.html#actions-taken
+const ACTIONS: &[&str] = &[
+    "waf",
+    "waf-failopen",
+    "authenticate",
+    "redirect",
+    "fixed-response",
+    "forward",
+];
+
+/// Splits a space-separated list, which is `-` when empty
+fn split_list(list: &str) -> Vec<&str> {
+    list.split(' ')
+        .filter(|item| !item.is_empty() && *item != "-")
+        .collect()
+}
+
+/// Splits a comma-separated actions_executed into documented actions, where an action which is not
+/// documented is "unknown".
+pub(crate) fn actions(actions: &str) -> Vec<Value> {
+    actions
+        .split(',')
+        .filter(|action| !action.is_empty() && *action != "-")
+        .map(|action| match ACTIONS.contains(&action) {
+            true => Value::from(action),
+            false => Value::from("unknown"),
+        })
+        .collect()
+}
+
+/// Splits `ip:port` or `[ipv6]:port`
+pub(crate) fn split_ip_port(ip_port: &str) -> (&str, Option<u16>) {
+    let (ip, port) = match ip_port.rsplit_once(':') {
+        Some((ip, port)) if !port.contains(']') => (ip, port.parse().ok()),
+        _ => (ip_port, None),
+    };
+    (ip.trim_start_matches('[').trim_end_matches(']'), port)
+}
+
+/// Zips target_ip_port_list with target_status_code_list into `[{ip, port, status}]`. A status
+/// code is null if it is missing, e.g. when the target did not respond. Targets which are `-` are
+/// dropped only after zipping, so that every status code stays with its own target.
+pub(crate) fn targets(ip_ports: &str, status_codes: &str) -> Vec<Value> {
+    let mut status_codes = status_codes.split(' ').filter(|s| !s.is_empty());
+    ip_ports
+        .split(' ')
+        .filter(|ip_port| !ip_port.is_empty())
+        .map(|ip_port| (ip_port, status_codes.next()))
+        .filter(|(ip_port, _)| *ip_port != "-")
+        .map(|(ip_port, status)| {
+            let (ip, port) = split_ip_port(ip_port);
+            let status = status.and_then(|s| s.parse::<u16>().ok());
+            json!({ "ip": ip, "port": port, "status": status })
+        })
+        .collect()
+}
+
+/// Replaces actions_executed, target_ip_port_list and target_status_code_list with arrays, and
+/// adds targets, which zips the latter two.
+pub(super) fn enrich(record: &mut Record) {
+    if let Some(list) = record.get_str("actions_executed") {
+        let actions = actions(list);
+        record.insert("actions_executed", actions);
+    }
+
+    let (Some(ip_ports), Some(status_codes)) = (
+        record.get_str("target_ip_port_list"),
+        record.get_str("target_status_code_list"),
+    ) else {
+        return;
+    };
+    let targets = targets(ip_ports, status_codes);
+    let to_array =
+        |list: &str| -> Vec<Value> { split_list(list).into_iter().map(Value::from).collect() };
+    let (ip_ports, status_codes) = (to_array(ip_ports), to_array(status_codes));
+    record.insert("target_ip_port_list", ip_ports);
+    record.insert("target_status_code_list", status_codes);
+    record.insert("targets", targets);
+}
+
+#[test]
+fn test_targets() {
+    assert_eq!(
+        Value::from(targets(
+            "10.0.1.100:80 [2001:db8::1]:8080 10.0.1.101:80",
+            "502 200"
+        )),
+        json!([
+            { "ip": "10.0.1.100", "port": 80, "status": 502 },
+            { "ip": "2001:db8::1", "port": 8080, "status": 200 },
+            { "ip": "10.0.1.101", "port": 80, "status": null },
+        ])
+    );
+    // A target without a status code in the middle keeps the following pairs aligned
+    assert_eq!(
+        Value::from(targets("10.0.1.100:80 - 10.0.1.101:80", "502 - 200")),
+        json!([
+            { "ip": "10.0.1.100", "port": 80, "status": 502 },
+            { "ip": "10.0.1.101", "port": 80, "status": 200 },
+        ])
+    );
+    assert_eq!(
+        Value::from(targets("10.0.1.100:80 10.0.1.101:80", "- 200")),
+        json!([
+            { "ip": "10.0.1.100", "port": 80, "status": null },
+            { "ip": "10.0.1.101", "port": 80, "status": 200 },
+        ])
+    );
+    assert_eq!(targets("-", "-"), Vec::<Value>::new());
+
+    assert_eq!(
+        Value::from(actions("waf,forward,magic")),
+        json!(["waf", "forward", "unknown"])
+    );
+    assert_eq!(actions("-"), Vec::<Value>::new());
+}
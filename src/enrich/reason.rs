@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+use crate::record::Record;
+
+/// Defines an enum of documented codes, with an `Unknown` fallback for codes which AWS added
+/// after this list was written.
+macro_rules! codes {
+    ($(#[$attr:meta])* $name:ident { $($variant:ident => $description:literal,)* }) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        pub(crate) enum $name {
+            $($variant,)*
+            Unknown,
+        }
+
+        impl $name {
+            pub(crate) fn parse(code: &str) -> Self {
+                match code {
+                    $(stringify!($variant) => Self::$variant,)*
+                    _ => Self::Unknown,
+                }
+            }
+
+            pub(crate) fn description(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $description,)*
+                    Self::Unknown => "unknown",
+                }
+            }
+
+            /// Documented meaning of a code, or `None` if it is unknown
+            pub(crate) fn describe(code: &str) -> Option<&'static str> {
+                match Self::parse(code) {
+                    Self::Unknown => None,
+                    code => Some(code.description()),
+                }
+            }
+        }
+    };
+}
+
+codes! {
+    /// Values of error_reason, documented at
+    /// https://docs.aws.amazon.com/elasticloadbalancing/latest/application/load-balancer-access-logs.html#error-reason-codes
+    ErrorReason {
+        AuthInvalidCookie => "The authentication cookie is not valid.",
+        AuthInvalidGrantError => "The authorization grant code from the token endpoint is not valid.",
+        AuthInvalidIdToken => "The ID token is not valid.",
+        AuthInvalidStateParam => "The state parameter is not valid.",
+        AuthInvalidTokenResponse => "The response from the token endpoint is not valid.",
+        AuthInvalidUserinfoResponse => "The response from the user info endpoint is not valid.",
+        AuthMissingCodeParam => "The authentication response from the IdP is missing a query parameter named 'code'.",
+        AuthMissingHostHeader => "The authentication response from the IdP is missing a host header field.",
+        AuthMissingStateParam => "The authentication response from the IdP is missing a query parameter named 'state'.",
+        AuthTokenEpRequestFailed => "There is an error response (non-2XX) from the token endpoint.",
+        AuthTokenEpRequestTimeout => "The load balancer is unable to communicate with the token endpoint, or the token endpoint is not responding within 5 seconds.",
+        AuthUnhandledException => "The load balancer encountered an unhandled exception.",
+        AuthUserinfoEpRequestFailed => "There is an error response (non-2XX) from the IdP user info endpoint.",
+        AuthUserinfoEpRequestTimeout => "The load balancer is unable to communicate with the IdP user info endpoint, or the user info endpoint is not responding within 5 seconds.",
+        AuthUserinfoResponseSizeExceeded => "The size of the claims returned by the IdP exceeded 11K bytes.",
+        LambdaAccessDenied => "The load balancer did not have permission to invoke the Lambda function.",
+        LambdaBadRequest => "Lambda invocation failed because the client request headers or body did not contain only UTF-8 characters.",
+        LambdaConnectionError => "The load balancer cannot connect to Lambda.",
+        LambdaConnectionTimeout => "An attempt to connect to Lambda timed out.",
+        LambdaEC2AccessDeniedException => "Amazon EC2 denied access to Lambda during function initialization.",
+        LambdaEC2ThrottledException => "Amazon EC2 throttled Lambda during function initialization.",
+        LambdaEC2UnexpectedException => "Amazon EC2 encountered an unexpected exception during function initialization.",
+        LambdaENILimitReachedException => "Lambda couldn't create a network interface in the VPC, because the network interface limit was exceeded.",
+        LambdaInvalidResponse => "The response from the Lambda function is malformed or is missing required fields.",
+        LambdaInvalidRuntimeException => "The specified version of the Lambda runtime is not supported.",
+        LambdaInvalidSecurityGroupIDException => "The security group ID specified in the Lambda function configuration is not valid.",
+        LambdaInvalidSubnetIDException => "The subnet ID specified in the Lambda function configuration is not valid.",
+        LambdaInvalidZipFileException => "Lambda could not unzip the specified function zip file.",
+        LambdaKMSAccessDeniedException => "Lambda could not decrypt environment variables because access to the KMS key was denied.",
+        LambdaKMSDisabledException => "Lambda could not decrypt environment variables because the KMS key is disabled.",
+        LambdaKMSInvalidStateException => "Lambda could not decrypt environment variables because the state of the KMS key is not valid.",
+        LambdaKMSNotFoundException => "Lambda could not decrypt environment variables because the KMS key was not found.",
+        LambdaRequestTooLarge => "The size of the request body exceeded 1 MB.",
+        LambdaResourceNotFound => "The Lambda function could not be found.",
+        LambdaResponseTooLarge => "The size of the response exceeded 1 MB.",
+        LambdaServiceException => "Lambda encountered an internal error.",
+        LambdaSubnetIPAddressLimitReachedException => "Lambda could not set up VPC access for the function, because one or more subnets have no available IP addresses.",
+        LambdaThrottling => "The Lambda function was throttled because there were too many requests.",
+        LambdaTimeout => "The Lambda function did not respond before its configured timeout.",
+        LambdaUnhandled => "The Lambda function encountered an unhandled exception.",
+        OutpostInsufficientCapacity => "The Outpost does not have enough capacity to scale the load balancer.",
+        TargetConnectionErrorCode => "The load balancer could not establish a connection with the target.",
+        WAFConnectionError => "The load balancer cannot connect to AWS WAF.",
+        WAFConnectionTimeout => "The connection to AWS WAF timed out.",
+        WAFResponseReadTimeout => "A request to AWS WAF timed out.",
+        WAFServiceError => "AWS WAF returned a 5XX error.",
+        WAFUnhandledException => "The load balancer encountered an unhandled exception.",
+    }
+}
+
+codes! {
+    /// Values of classification_reason of the desync mitigation mode, documented at
+    /// https://docs.aws.amazon.com/elasticloadbalancing/latest/application/load-balancer-access-logs.html#classification-reasons
+    ClassificationReason {
+        AmbiguousUri => "The request URI contains control characters.",
+        BadContentLength => "The Content-Length header contains a value that cannot be parsed or is not a valid number.",
+        BadHeader => "A header contains a null character or carriage return.",
+        BadTransferEncoding => "The Transfer-Encoding header contains a bad value.",
+        BadUri => "The request URI contains a null character or carriage return.",
+        BadMethod => "The request method is malformed.",
+        BadVersion => "The request version is malformed.",
+        BothTeClPresent => "The request contains both a Transfer-Encoding header and a Content-Length header.",
+        DuplicateContentLength => "There are multiple Content-Length headers with the same value.",
+        EmptyHeader => "A header is empty or there is a line with only spaces.",
+        GetHeadZeroContentLength => "There is a Content-Length header with a value of 0 for a GET or HEAD request.",
+        MultipleContentLength => "There are multiple Content-Length headers with different values.",
+        MultipleTransferEncodingChunked => "There are multiple Transfer-Encoding: chunked headers.",
+        NonCompliantHeader => "A header violates RFC 7230.",
+        NonCompliantVersion => "The request version contains a bad value.",
+        SpaceInUri => "The request URI contains a space that is not URL encoded.",
+        SuspiciousHeader => "There is a header that can be normalized to Transfer-Encoding or Content-Length using common text normalization techniques.",
+        UndefinedContentLengthSemantics => "There is a Content-Length header defined for a GET or HEAD request.",
+        UndefinedTransferEncodingSemantics => "There is a Transfer-Encoding header defined for a GET or HEAD request.",
+    }
+}
+
+/// Attaches the documented meaning of error_reason and classification_reason, and counts codes
+/// which are not documented
+#[derive(Default)]
+pub(crate) struct Explainer {
+    unknown: Mutex<BTreeMap<(&'static str, String), u64>>,
+}
+
+impl Explainer {
+    pub(super) fn enrich(&self, record: &mut Record) {
+        let explain = |field: &str, describe: fn(&str) -> Option<&'static str>| {
+            let code = record.get_str(field)?;
+            Some((code, (code != "-").then(|| describe(code))))
+        };
+        let explanations = [
+            (
+                "error_reason",
+                "error_reason_description",
+                explain("error_reason", ErrorReason::describe),
+            ),
+            (
+                "classification_reason",
+                "classification_reason_description",
+                explain("classification_reason", ClassificationReason::describe),
+            ),
+        ];
+
+        let mut fields = vec![];
+        for (field, name, explanation) in explanations {
+            let description = match explanation {
+                None => continue,
+                Some((_, None)) => Value::Null,
+                Some((_, Some(Some(description)))) => Value::from(description),
+                Some((code, Some(None))) => {
+                    let mut unknown = self.unknown.lock().unwrap();
+                    *unknown.entry((field, code.to_owned())).or_default() += 1;
+                    Value::from(ErrorReason::Unknown.description())
+                }
+            };
+            fields.push((name, description));
+        }
+        for (name, value) in fields {
+            record.insert(name, value);
+        }
+    }
+
+    /// Reports the codes which are not documented, so that new codes from AWS get noticed
+    pub(super) fn summary(&self) {
+        for warning in self.warnings() {
+            eprintln!("Warning: {warning}");
+        }
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.unknown
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((field, code), count)| {
+                let s = if *count == 1 { "" } else { "s" };
+                format!("unknown {field} `{code}` appeared {count} time{s}")
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_reason_codes() {
+    assert_eq!(
+        ErrorReason::parse("LambdaUnhandled"),
+        ErrorReason::LambdaUnhandled
+    );
+    assert_eq!(
+        ErrorReason::parse("TargetConnectionErrorCode"),
+        ErrorReason::TargetConnectionErrorCode
+    );
+    assert_eq!(ErrorReason::parse("SomethingNew"), ErrorReason::Unknown);
+    assert_eq!(ErrorReason::Unknown.description(), "unknown");
+    assert_eq!(
+        ClassificationReason::parse("UndefinedContentLengthSemantics").description(),
+        "There is a Content-Length header defined for a GET or HEAD request."
+    );
+}
+
+#[test]
+fn test_explainer() {
+    let explainer = Explainer::default();
+    let explain = |error_reason: &str, classification_reason: &str| {
+        let mut record = Record::default();
+        record.insert("error_reason", error_reason.to_owned());
+        record.insert("classification_reason", classification_reason.to_owned());
+        explainer.enrich(&mut record);
+        (
+            record.get("error_reason_description").cloned(),
+            record.get("classification_reason_description").cloned(),
+        )
+    };
+
+    assert_eq!(
+        explain("LambdaTimeout", "-"),
+        (
+            Some(Value::from(
+                "The Lambda function did not respond before its configured timeout."
+            )),
+            Some(Value::Null)
+        )
+    );
+    assert_eq!(
+        explain("SomethingNew", "SomethingElse"),
+        (Some(Value::from("unknown")), Some(Value::from("unknown")))
+    );
+    explain("SomethingNew", "-");
+    assert_eq!(
+        explainer.warnings(),
+        [
+            "unknown classification_reason `SomethingElse` appeared 1 time",
+            "unknown error_reason `SomethingNew` appeared 2 times",
+        ]
+    );
+}
@@ -0,0 +1,160 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use super::url;
+use crate::record::Record;
+
+/// Normalizes request paths into route templates, so that requests can be grouped by route.
+///
+/// User-supplied patterns are tried first, in order. A pattern is a path whose segments are either
+/// literals, `{name}` which matches any single segment, or a trailing `*` which matches the rest
+/// of the path, e.g. `/users/{id}/orders/*`. If no pattern matches, each segment of the path is
+/// replaced by the built-in heuristics instead:
+///
+/// - `{id}` for decimal numbers
+/// - `{uuid}` for UUIDs
+/// - `{hash}` for hexadecimal strings of at least 16 digits
+#[derive(Default)]
+pub(crate) struct Routes {
+    patterns: Vec<Pattern>,
+}
+
+struct Pattern {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+enum Segment {
+    Literal(String),
+    Param,
+    Rest,
+}
+
+impl Routes {
+    /// Loads patterns from a file, one per line. Empty lines and lines starting with `#` are
+    /// ignored.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let content = read_to_string(path)
+            .with_context(|| format!("Failed to read routes from {}", path.display()))?;
+        let patterns = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Pattern::parse)
+            .collect::<Result<_>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub(crate) fn route(&self, path: &str) -> String {
+        let segments: Vec<_> = path.split('/').skip(1).collect();
+        self.patterns
+            .iter()
+            .find(|pattern| pattern.matches(&segments))
+            .map(|pattern| pattern.template.clone())
+            .unwrap_or_else(|| heuristic(&segments))
+    }
+
+    pub(super) fn enrich(&self, record: &mut Record) {
+        let route = record.get_str("url").and_then(|url| {
+            let url = url::split(url);
+            let path = url.path.or(url.host.map(|_| "/"))?;
+            Some(self.route(path))
+        });
+        record.insert("route", route);
+    }
+}
+
+impl Pattern {
+    fn parse(template: &str) -> Result<Self> {
+        if !template.starts_with('/') {
+            bail!("Route pattern must start with '/': {template}");
+        }
+        let segments: Vec<_> = template.split('/').skip(1).collect();
+        let segments = segments
+            .iter()
+            .enumerate()
+            .map(|(i, &segment)| match segment {
+                "*" if i + 1 == segments.len() => Ok(Segment::Rest),
+                "*" => bail!("'*' is only allowed at the end of a route pattern: {template}"),
+                s if s.starts_with('{') && s.ends_with('}') => Ok(Segment::Param),
+                s => Ok(Segment::Literal(s.to_owned())),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            template: template.to_owned(),
+            segments,
+        })
+    }
+
+    fn matches(&self, path: &[&str]) -> bool {
+        let mut path = path.iter();
+        for segment in &self.segments {
+            match (segment, path.next()) {
+                (Segment::Rest, _) => return true,
+                (Segment::Param, Some(_)) => {}
+                (Segment::Literal(literal), Some(s)) if literal == s => {}
+                _ => return false,
+            }
+        }
+        path.next().is_none()
+    }
+}
+
+fn heuristic(segments: &[&str]) -> String {
+    let mut route = String::new();
+    for &segment in segments {
+        route.push('/');
+        route.push_str(
+            if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+                "{id}"
+            } else if is_uuid(segment) {
+                "{uuid}"
+            } else if segment.len() >= 16 && segment.bytes().all(|b| b.is_ascii_hexdigit()) {
+                "{hash}"
+            } else {
+                segment
+            },
+        );
+    }
+    if route.is_empty() {
+        route.push('/');
+    }
+    route
+}
+
+fn is_uuid(s: &str) -> bool {
+    let s = s.as_bytes();
+    s.len() == 36
+        && s.iter().enumerate().all(|(i, &b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+#[test]
+fn test_route() {
+    let routes = Routes {
+        patterns: ["/users/{id}/profile", "/static/*", "/health"]
+            .into_iter()
+            .map(|p| Pattern::parse(p).unwrap())
+            .collect(),
+    };
+    let t = |path, expected| assert_eq!(routes.route(path), expected);
+
+    t("/users/alice/profile", "/users/{id}/profile");
+    t("/static/js/app.js", "/static/*");
+    t("/health", "/health");
+    t("/health/deep", "/health/deep");
+    t(
+        "/users/12345/orders/0b7e5a6c-8f7e-4b8a-9f3c-2d1e0a9b8c7d",
+        "/users/{id}/orders/{uuid}",
+    );
+    t("/blobs/d41d8cd98f00b204e9800998ecf8427e/", "/blobs/{hash}/");
+    t("/api/v1", "/api/v1");
+    t("/", "/");
+
+    assert!(Pattern::parse("users").is_err());
+    assert!(Pattern::parse("/*/users").is_err());
+}
@@ -0,0 +1,135 @@
+use jiff::fmt::strtime;
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, Zoned};
+use serde_json::Value;
+
+use crate::record::Record;
+
+/// Fields holding timestamps
+pub(crate) const TIME_FIELDS: &[&str] = &["time", "request_creation_time"];
+
+/// How timestamps are rendered
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum TimeFormat {
+    /// Seconds since the Unix epoch, as an integer
+    Epoch,
+    /// Milliseconds since the Unix epoch, as an integer
+    EpochMs,
+    /// Microseconds since the Unix epoch, as an integer
+    EpochUs,
+    /// RFC 3339 with the offset of the time zone, e.g. `2020-01-11T10:11:10.111111+09:00`
+    Rfc3339,
+    /// strftime pattern, e.g. `%Y-%m-%d %H:%M:%S`
+    Custom(String),
+}
+
+const RFC3339: &str = "%Y-%m-%dT%H:%M:%S%.6f%:z";
+
+/// Parses `epoch`, `epoch_ms`, `epoch_us`, `rfc3339`, or a strftime pattern.
+pub(crate) fn parse_time_format(s: &str) -> Result<TimeFormat, String> {
+    Ok(match s {
+        "epoch" => TimeFormat::Epoch,
+        "epoch_ms" => TimeFormat::EpochMs,
+        "epoch_us" => TimeFormat::EpochUs,
+        "rfc3339" => TimeFormat::Rfc3339,
+        pattern if pattern.contains('%') => {
+            // Reject invalid patterns now, rather than failing on every record
+            strtime::format(pattern, &Zoned::now()).map_err(|e| e.to_string())?;
+            TimeFormat::Custom(pattern.to_owned())
+        }
+        _ => {
+            return Err(format!(
+                "{s} is neither epoch, epoch_ms, epoch_us, rfc3339, nor a strftime pattern"
+            ));
+        }
+    })
+}
+
+pub(crate) fn parse_tz(s: &str) -> Result<TimeZone, String> {
+    TimeZone::get(s).map_err(|e| e.to_string())
+}
+
+pub(super) struct Options {
+    pub(super) tz: TimeZone,
+    pub(super) format: Option<TimeFormat>,
+    pub(super) queue_time: bool,
+}
+
+impl Options {
+    fn render(&self, time: Timestamp) -> Value {
+        let format = self.format.as_ref().unwrap_or(&TimeFormat::Rfc3339);
+        let zoned = || time.to_zoned(self.tz.clone());
+        match format {
+            TimeFormat::Epoch => Value::from(time.as_second()),
+            TimeFormat::EpochMs => Value::from(time.as_millisecond()),
+            TimeFormat::EpochUs => Value::from(time.as_microsecond()),
+            TimeFormat::Rfc3339 => Value::from(zoned().strftime(RFC3339).to_string()),
+            TimeFormat::Custom(pattern) => {
+                strtime::format(pattern, &zoned()).map_or(Value::Null, Value::from)
+            }
+        }
+    }
+}
+
+/// Seconds from `request_creation_time` to `time`, i.e. how long the load balancer took to
+/// respond after receiving the request
+fn queue_time(record: &Record) -> Option<f64> {
+    let time: Timestamp = record.get_str("time")?.parse().ok()?;
+    let created: Timestamp = record.get_str("request_creation_time")?.parse().ok()?;
+    Some(time.duration_since(created).as_secs_f64())
+}
+
+pub(super) fn enrich(record: &mut Record, options: &Options) {
+    let queue_time = options.queue_time.then(|| queue_time(record));
+
+    if options.format.is_some() {
+        for &field in TIME_FIELDS {
+            let Some(time) = record.get_str(field) else {
+                continue;
+            };
+            // Timestamps which cannot be parsed, e.g. "-", are left as they are
+            if let Ok(time) = time.parse() {
+                let value = options.render(time);
+                record.insert(field, value);
+            }
+        }
+    }
+    if let Some(queue_time) = queue_time {
+        record.insert("queue_time", queue_time);
+    }
+}
+
+#[test]
+fn test_time_format() {
+    let time: Timestamp = "2020-01-11T01:11:10.111111Z".parse().unwrap();
+    let t = |tz: &str, format: &str, expected: Value| {
+        let options = Options {
+            tz: parse_tz(tz).unwrap(),
+            format: Some(parse_time_format(format).unwrap()),
+            queue_time: false,
+        };
+        assert_eq!(options.render(time), expected);
+    };
+
+    t("UTC", "epoch", Value::from(1578705070));
+    t("UTC", "epoch_ms", Value::from(1578705070111_i64));
+    t("UTC", "epoch_us", Value::from(1578705070111111_i64));
+    t(
+        "UTC",
+        "rfc3339",
+        Value::from("2020-01-11T01:11:10.111111+00:00"),
+    );
+    t(
+        "Asia/Seoul",
+        "rfc3339",
+        Value::from("2020-01-11T10:11:10.111111+09:00"),
+    );
+    t(
+        "Asia/Seoul",
+        "%Y-%m-%d %H:%M:%S",
+        Value::from("2020-01-11 10:11:10"),
+    );
+
+    assert!(parse_time_format("epoch_ns").is_err());
+    assert!(parse_tz("Mars/Olympus_Mons").is_err());
+}
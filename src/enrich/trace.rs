@@ -0,0 +1,130 @@
+use serde_json::Value;
+
+use crate::record::Record;
+
+/// Components of an X-Amzn-Trace-Id header, e.g.
+/// `Root=1-67891233-abcdef012345678912345678;Parent=53995c3f42cd8ad8;Sampled=1`
+#[derive(Default, PartialEq, Debug)]
+pub(crate) struct TraceId<'a> {
+    pub(crate) root: Option<&'a str>,
+    pub(crate) self_: Option<&'a str>,
+    pub(crate) parent: Option<&'a str>,
+    pub(crate) sampled: Option<bool>,
+}
+
+/// Parses an X-Amzn-Trace-Id header. Unknown keys are ignored, and so are values which are
+/// malformed, since clients can send anything.
+pub(crate) fn parse(trace_id: &str) -> TraceId<'_> {
+    let mut ret = TraceId::default();
+    for pair in trace_id.split(';') {
+        let Some((key, value)) = pair.trim().split_once('=') else {
+            continue;
+        };
+        match key {
+            "Root" => ret.root = Some(value),
+            "Self" => ret.self_ = Some(value),
+            "Parent" => ret.parent = Some(value),
+            "Sampled" => {
+                ret.sampled = match value {
+                    "1" => Some(true),
+                    "0" => Some(false),
+                    _ => None,
+                }
+            }
+            _ => {}
+        }
+    }
+    ret
+}
+
+/// Splits a root ID of `1-{8 hex digits of epoch seconds}-{24 hex digits}` into the epoch seconds
+/// and the 32 hex digits of the W3C trace ID
+pub(crate) fn split_root(root: &str) -> Option<(u64, String)> {
+    let (time, id) = root.strip_prefix("1-")?.split_once('-')?;
+    if time.len() != 8 || id.len() != 24 || !is_hex(time) || !is_hex(id) {
+        return None;
+    }
+    let epoch = u64::from_str_radix(time, 16).ok()?;
+    Some((epoch, format!("{time}{id}").to_ascii_lowercase()))
+}
+
+/// Converts into a W3C traceparent header. It requires a parent ID, so there is none for requests
+/// without Parent, which is an X-Ray segment ID of 16 hex digits just like W3C parent IDs.
+fn traceparent(trace_id: &TraceId, w3c_id: &str) -> Option<String> {
+    let parent = trace_id.parent.filter(|p| p.len() == 16 && is_hex(p))?;
+    let flags = if trace_id.sampled == Some(true) {
+        "01"
+    } else {
+        "00"
+    };
+    Some(format!(
+        "00-{w3c_id}-{}-{flags}",
+        parent.to_ascii_lowercase()
+    ))
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub(super) fn enrich(record: &mut Record) {
+    let Some(trace_id) = record.get_str("trace_id") else {
+        return;
+    };
+    let trace_id = parse(trace_id);
+    let root = trace_id.root.and_then(split_root);
+    let traceparent = root
+        .as_ref()
+        .and_then(|(_, w3c_id)| traceparent(&trace_id, w3c_id));
+
+    let str = |s: Option<&str>| s.map_or(Value::Null, Value::from);
+    let fields = [
+        ("trace_root", str(trace_id.root)),
+        ("trace_self", str(trace_id.self_)),
+        ("trace_parent", str(trace_id.parent)),
+        (
+            "trace_sampled",
+            trace_id.sampled.map_or(Value::Null, Value::from),
+        ),
+        (
+            "trace_time",
+            root.as_ref().map_or(Value::Null, |(t, _)| Value::from(*t)),
+        ),
+        (
+            "trace_w3c_id",
+            str(root.as_ref().map(|(_, id)| id.as_str())),
+        ),
+        ("traceparent", str(traceparent.as_deref())),
+    ];
+    for (name, value) in fields {
+        record.insert(name, value);
+    }
+}
+
+#[test]
+fn test_trace_id() {
+    let trace_id = parse(
+        "Self=1-67891234-12456789abcdef012345678;Root=1-67891233-ABCDEF012345678912345678;Parent=53995C3F42CD8AD8;Sampled=1;Lineage=a87bd80c:1",
+    );
+    assert_eq!(
+        trace_id,
+        TraceId {
+            root: Some("1-67891233-ABCDEF012345678912345678"),
+            self_: Some("1-67891234-12456789abcdef012345678"),
+            parent: Some("53995C3F42CD8AD8"),
+            sampled: Some(true),
+        }
+    );
+
+    let (epoch, w3c_id) = split_root(trace_id.root.unwrap()).unwrap();
+    assert_eq!(epoch, 1737036339);
+    assert_eq!(w3c_id, "67891233abcdef012345678912345678");
+    assert_eq!(
+        traceparent(&trace_id, &w3c_id).as_deref(),
+        Some("00-67891233abcdef012345678912345678-53995c3f42cd8ad8-01")
+    );
+
+    assert_eq!(parse("-"), TraceId::default());
+    assert_eq!(split_root("1-67891233-abcdef"), None);
+    assert_eq!(split_root("Root=2-67891233-abcdef012345678912345678"), None);
+}
@@ -0,0 +1,204 @@
+use percent_encoding::percent_decode_str;
+use serde_json::{Map, Value};
+
+use crate::record::Record;
+
+pub(super) struct Options {
+    pub(super) query_params: bool,
+    pub(super) percent_decode: bool,
+}
+
+/// Components of a URL, borrowed from the original URL as is
+#[derive(Default, PartialEq, Debug)]
+pub(crate) struct Url<'a> {
+    pub(crate) scheme: Option<&'a str>,
+    pub(crate) host: Option<&'a str>,
+    pub(crate) port: Option<&'a str>,
+    pub(crate) path: Option<&'a str>,
+    pub(crate) query: Option<&'a str>,
+}
+
+impl<'a> Url<'a> {
+    /// Host without the brackets around an IPv6 literal, e.g. `::1` of `[::1]:443`
+    pub(crate) fn hostname(&self) -> Option<&'a str> {
+        self.host
+            .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+    }
+}
+
+/// Splits a URL into its components.
+///
+/// Load balancers log whatever clients sent, so this never fails. Components which cannot be
+/// found are `None`, e.g. a port which is not a number.
+pub(crate) fn split(url: &str) -> Url<'_> {
+    let mut ret = Url::default();
+    if url.is_empty() || url == "-" {
+        return ret;
+    }
+
+    // Origin form (/path), absolute form (https://example.com/path), or authority form
+    // (example.com:443). Origin form comes first, since its query may contain another URL.
+    let (authority, rest) = if url.starts_with('/') {
+        (None, url)
+    } else if let Some((scheme, rest)) = url.split_once("://").filter(|(s, _)| is_scheme(s)) {
+        ret.scheme = Some(scheme);
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        (Some(&rest[..end]), &rest[end..])
+    } else {
+        (Some(url), "")
+    };
+
+    if let Some(authority) = authority {
+        // IPv6 literals are enclosed in brackets, e.g. [::1]:443
+        let port_sep = match authority.rfind(']') {
+            Some(bracket) => authority[bracket..].find(':').map(|i| bracket + i),
+            None => authority.rfind(':'),
+        };
+        match port_sep {
+            Some(i) => {
+                let port = &authority[i + 1..];
+                ret.host = Some(&authority[..i]);
+                ret.port =
+                    (!port.is_empty() && port.bytes().all(|b| b.is_ascii_digit())).then_some(port);
+            }
+            None => ret.host = Some(authority),
+        }
+    }
+
+    let rest = rest.split_once('#').map_or(rest, |(rest, _fragment)| rest);
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+    ret.path = (!path.is_empty()).then_some(path);
+    ret.query = query;
+    ret
+}
+
+/// Whether `s` is a scheme, i.e. `[A-Za-z][A-Za-z0-9+.-]*`
+fn is_scheme(s: &str) -> bool {
+    let mut bytes = s.bytes();
+    bytes.next().is_some_and(|b| b.is_ascii_alphabetic())
+        && bytes.all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'.' | b'-'))
+}
+
+pub(super) fn enrich(record: &mut Record, options: &Options) {
+    let Some(url) = record.get_str("url") else {
+        return;
+    };
+    let url = split(url);
+
+    let opt = |s: Option<&str>| s.map_or(Value::Null, Value::from);
+    let path = match url.path {
+        Some(path) if options.percent_decode => Value::from(decode(path, false)),
+        path => opt(path),
+    };
+    let query_params = options
+        .query_params
+        .then(|| query_params(url.query.unwrap_or(""), options.percent_decode));
+    let fields = [
+        ("url_scheme", opt(url.scheme)),
+        ("url_host", opt(url.host)),
+        ("url_port", opt(url.port)),
+        ("url_path", path),
+        ("url_query", opt(url.query)),
+    ];
+
+    for (name, value) in fields {
+        record.insert(name, value);
+    }
+    if let Some(query_params) = query_params {
+        record.insert("url_query_params", query_params);
+    }
+}
+
+/// Parses a query string into a map from each key to the list of its values
+fn query_params(query: &str, percent_decode: bool) -> Map<String, Value> {
+    let mut params = Map::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let (key, value) = if percent_decode {
+            (decode(key, true), decode(value, true))
+        } else {
+            (key.to_owned(), value.to_owned())
+        };
+        let values = params.entry(key).or_insert_with(|| Value::Array(vec![]));
+        if let Value::Array(values) = values {
+            values.push(Value::from(value));
+        }
+    }
+    params
+}
+
+fn decode(s: &str, plus_as_space: bool) -> String {
+    if plus_as_space && s.contains('+') {
+        let s = s.replace('+', " ");
+        percent_decode_str(&s).decode_utf8_lossy().into_owned()
+    } else {
+        percent_decode_str(s).decode_utf8_lossy().into_owned()
+    }
+}
+
+#[test]
+fn test_split() {
+    let t = |url, expected: [Option<&str>; 5]| {
+        let [scheme, host, port, path, query] = expected;
+        assert_eq!(
+            split(url),
+            Url {
+                scheme,
+                host,
+                port,
+                path,
+                query
+            }
+        );
+    };
+
+    t(
+        "https://example.com:443/very/good/route?some=pArameter12345&_=0123456788912",
+        [
+            Some("https"),
+            Some("example.com"),
+            Some("443"),
+            Some("/very/good/route"),
+            Some("some=pArameter12345&_=0123456788912"),
+        ],
+    );
+    t(
+        "https://example.com",
+        [Some("https"), Some("example.com"), None, None, None],
+    );
+    t(
+        "http://example.com:8080-",
+        [Some("http"), Some("example.com"), None, None, None],
+    );
+    t(
+        "https://[2001:db8::1]:443/?",
+        [
+            Some("https"),
+            Some("[2001:db8::1]"),
+            Some("443"),
+            Some("/"),
+            Some(""),
+        ],
+    );
+    t(
+        "/index.html?a=1#top",
+        [None, None, None, Some("/index.html"), Some("a=1")],
+    );
+    t(
+        "example.com:443",
+        [None, Some("example.com"), Some("443"), None, None],
+    );
+    t(
+        "/a?next=https://x",
+        [None, None, None, Some("/a"), Some("next=https://x")],
+    );
+    t("-", [None, None, None, None, None]);
+
+    assert_eq!(
+        serde_json::to_string(&query_params("a=1&b=%ED%95%9C+x&a&&c=", true)).unwrap(),
+        r#"{"a":["1",""],"b":["한 x"],"c":[""]}"#
+    );
+}
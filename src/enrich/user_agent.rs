@@ -0,0 +1,302 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::Cache;
+use crate::record::Record;
+
+const BUNDLED: &str = include_str!("user_agents.yaml");
+
+/// Parses user agents into browser, OS, device and bot class, using a database in the format of
+/// uap-core's regexes.yaml
+pub(crate) struct UserAgentParser {
+    bots: Vec<(Regex, String)>,
+    user_agents: Vec<Rule>,
+    os: Vec<Rule>,
+    devices: Vec<Rule>,
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub(crate) struct UserAgent {
+    pub(crate) family: Option<String>,
+    pub(crate) version: Option<String>,
+    pub(crate) os: Option<String>,
+    pub(crate) os_version: Option<String>,
+    pub(crate) device: Option<String>,
+    /// `monitor`, `crawler`, `scanner` or any other class defined in the database
+    pub(crate) bot_class: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Database {
+    #[serde(default)]
+    bot_parsers: Vec<BotEntry>,
+    #[serde(default)]
+    user_agent_parsers: Vec<Entry>,
+    #[serde(default)]
+    os_parsers: Vec<Entry>,
+    #[serde(default)]
+    device_parsers: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+struct BotEntry {
+    regex: String,
+    class: String,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    regex: String,
+    regex_flag: Option<String>,
+    family_replacement: Option<String>,
+    v1_replacement: Option<String>,
+    v2_replacement: Option<String>,
+    v3_replacement: Option<String>,
+    os_replacement: Option<String>,
+    os_v1_replacement: Option<String>,
+    os_v2_replacement: Option<String>,
+    os_v3_replacement: Option<String>,
+    os_v4_replacement: Option<String>,
+    device_replacement: Option<String>,
+}
+
+/// A regex and the replacements of its outputs. An output without a replacement is the capture
+/// group of the same position, e.g. the second output is `$2`.
+struct Rule {
+    regex: Regex,
+    replacements: Vec<Option<String>>,
+}
+
+impl UserAgentParser {
+    pub(crate) fn bundled() -> Self {
+        Self::from_yaml(BUNDLED).unwrap()
+    }
+
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let yaml = read_to_string(path)
+            .with_context(|| format!("Failed to read user agent database {}", path.display()))?;
+        Self::from_yaml(&yaml)
+            .with_context(|| format!("Invalid user agent database {}", path.display()))
+    }
+
+    fn from_yaml(yaml: &str) -> Result<Self> {
+        let db: Database = serde_yaml::from_str(yaml)?;
+
+        // Some regexes of uap-core use features which the regex crate does not support, such as
+        // look-around. They are skipped, rather than rejecting the whole database.
+        let mut skipped = 0;
+        let mut compile = |regex: &str, flag: Option<&str>| {
+            let regex = match flag {
+                Some("i") => Regex::new(&format!("(?i){regex}")),
+                _ => Regex::new(regex),
+            };
+            regex.map_err(|_| skipped += 1).ok()
+        };
+        let rules = |entries: Vec<Entry>,
+                     compile: &mut dyn FnMut(&str, Option<&str>) -> Option<Regex>,
+                     replacements: fn(Entry) -> Vec<Option<String>>| {
+            entries
+                .into_iter()
+                .filter_map(|entry| {
+                    let regex = compile(&entry.regex, entry.regex_flag.as_deref())?;
+                    Some(Rule {
+                        regex,
+                        replacements: replacements(entry),
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let bots = db
+            .bot_parsers
+            .into_iter()
+            .filter_map(|entry| Some((compile(&entry.regex, None)?, entry.class)))
+            .collect();
+        let user_agents = rules(db.user_agent_parsers, &mut compile, |e| {
+            vec![
+                e.family_replacement,
+                e.v1_replacement,
+                e.v2_replacement,
+                e.v3_replacement,
+            ]
+        });
+        let os = rules(db.os_parsers, &mut compile, |e| {
+            vec![
+                e.os_replacement,
+                e.os_v1_replacement,
+                e.os_v2_replacement,
+                e.os_v3_replacement,
+                e.os_v4_replacement,
+            ]
+        });
+        let devices = rules(db.device_parsers, &mut compile, |e| {
+            vec![e.device_replacement]
+        });
+        if skipped > 0 {
+            eprintln!("Warning: skipped {skipped} unsupported regexes of the user agent database");
+        }
+
+        Ok(Self {
+            bots,
+            user_agents,
+            os,
+            devices,
+        })
+    }
+
+    pub(crate) fn parse(&self, user_agent: &str) -> UserAgent {
+        let mut ret = UserAgent::default();
+        if let Some((caps, rule)) = first_match(&self.user_agents, user_agent) {
+            ret.family = rule.output(&caps, 0);
+            ret.version = version((1..4).map(|i| rule.output(&caps, i)));
+        }
+        if let Some((caps, rule)) = first_match(&self.os, user_agent) {
+            ret.os = rule.output(&caps, 0);
+            ret.os_version = version((1..5).map(|i| rule.output(&caps, i)));
+        }
+        if let Some((caps, rule)) = first_match(&self.devices, user_agent) {
+            ret.device = rule.output(&caps, 0);
+        }
+        ret.bot_class = self
+            .bots
+            .iter()
+            .find(|(regex, _)| regex.is_match(user_agent))
+            .map(|(_, class)| class.clone())
+            .or_else(|| (ret.device.as_deref() == Some("Spider")).then(|| "crawler".to_owned()));
+        ret
+    }
+
+    pub(super) fn enrich(&self, record: &mut Record, cache: &mut Cache<UserAgent>) {
+        let Some(user_agent) = record.get_str("user_agent") else {
+            return;
+        };
+        let ua = cache.get_or_insert_with(user_agent, || self.parse(user_agent));
+        let fields = [
+            ("ua_family", ua.family.clone()),
+            ("ua_version", ua.version.clone()),
+            ("ua_os", ua.os.clone()),
+            ("ua_os_version", ua.os_version.clone()),
+            ("ua_device", ua.device.clone()),
+            ("ua_bot_class", ua.bot_class.clone()),
+        ];
+        for (name, value) in fields {
+            record.insert(name, value.map_or(Value::Null, Value::from));
+        }
+    }
+}
+
+impl Rule {
+    fn output(&self, caps: &Captures, i: usize) -> Option<String> {
+        let output = match &self.replacements[i] {
+            Some(replacement) => {
+                let mut output = String::new();
+                caps.expand(replacement, &mut output);
+                output.trim().to_owned()
+            }
+            None => caps.get(i + 1)?.as_str().to_owned(),
+        };
+        (!output.is_empty()).then_some(output)
+    }
+}
+
+fn first_match<'a, 'h>(rules: &'a [Rule], haystack: &'h str) -> Option<(Captures<'h>, &'a Rule)> {
+    rules
+        .iter()
+        .find_map(|rule| Some((rule.regex.captures(haystack)?, rule)))
+}
+
+/// Joins version components with dots, stopping at the first missing one
+fn version(components: impl Iterator<Item = Option<String>>) -> Option<String> {
+    let components: Vec<_> = components.map_while(|c| c).collect();
+    (!components.is_empty()).then(|| components.join("."))
+}
+
+#[test]
+fn test_user_agent() {
+    let parser = UserAgentParser::bundled();
+    let t = |ua, family, version, os, os_version, device, bot_class| {
+        let s = |s: &str| (!s.is_empty()).then(|| s.to_owned());
+        assert_eq!(
+            parser.parse(ua),
+            UserAgent {
+                family: s(family),
+                version: s(version),
+                os: s(os),
+                os_version: s(os_version),
+                device: s(device),
+                bot_class: s(bot_class),
+            }
+        );
+    };
+
+    t(
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/60.0.3112.113 Safari/537.36",
+        "Chrome",
+        "60.0.3112",
+        "Windows",
+        "10",
+        "",
+        "",
+    );
+    t(
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 15_6_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Mobile/15E148 MYAPP/4.2.1 iOS/15.6.1 iPhone12,3",
+        "Mobile Safari UI/WKWebView",
+        "",
+        "iOS",
+        "15.6.1",
+        "iPhone",
+        "",
+    );
+    t(
+        "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Safari/605.1.15",
+        "Safari",
+        "17.1",
+        "Mac OS X",
+        "10.15.7",
+        "Mac",
+        "",
+    );
+    t(
+        "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        "Googlebot",
+        "2.1",
+        "",
+        "",
+        "Spider",
+        "crawler",
+    );
+    t(
+        "ELB-HealthChecker/2.0",
+        "ELB-HealthChecker",
+        "2.0",
+        "",
+        "",
+        "Spider",
+        "monitor",
+    );
+    t(
+        "Mozilla/5.0+(compatible; UptimeRobot/2.0; http://www.uptimerobot.com/)",
+        "UptimeRobot",
+        "2.0",
+        "",
+        "",
+        "Spider",
+        "monitor",
+    );
+    t(
+        "Pingdom.com_bot_version_1.4_(http://www.pingdom.com/)",
+        "Pingdom",
+        "1.4",
+        "",
+        "",
+        "Spider",
+        "monitor",
+    );
+    t("curl/8.5.0", "curl", "8.5.0", "", "", "", "");
+    t("-", "", "", "", "", "", "");
+}
@@ -0,0 +1,316 @@
+mod combined;
+mod ecs;
+mod es_bulk;
+mod otlp;
+mod raw;
+
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum, builder::ValueHint};
+use serde_json::Value;
+
+use self::combined::StatusCode;
+use self::es_bulk::parse_index;
+use crate::enrich::TIME_FIELDS;
+use crate::output::{Compress, PartitionKey, parse_size};
+use crate::parse::{LBLogParser, LogFields};
+use crate::record::{Decoding, Record};
+use crate::unescape::{ESCAPED_FIELDS, escape};
+
+/// Options for the format of each record
+#[derive(Args, Clone, Default, Debug)]
+#[command(next_help_heading = "Output")]
+pub(crate) struct FormatConfig {
+    /// Output format.
+    #[arg(value_enum, long, default_value_t = Format::Json)]
+    pub(crate) format: Format,
+
+    /// Status code written by --format combined.
+    #[arg(value_enum, long, value_name = "FIELD", default_value_t = StatusCode::Elb)]
+    pub(crate) combined_status: StatusCode,
+
+    /// Append the total processing time in seconds to each line of --format combined, as
+    /// $request_time of Nginx.
+    #[arg(long)]
+    pub(crate) combined_latency: bool,
+
+    /// Name of the index which --format es-bulk writes each record into, which may contain
+    /// strftime specifiers for the time of the record in UTC, e.g. "alb-%Y.%m.%d".
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        value_parser = parse_index,
+        required_if_eq("format", "es-bulk")
+    )]
+    pub(crate) index: Option<String>,
+
+    /// Write the output of --format es-bulk into numbered files in DIR, each of which is small
+    /// enough for a single request of the bulk API.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub(crate) bulk_dir: Option<PathBuf>,
+
+    /// Maximum size of each file in --bulk-dir, e.g. "10MiB".
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "10MiB")]
+    pub(crate) bulk_size: u64,
+
+    /// Write records into gzip compressed files in DIR instead of stdout, e.g.
+    /// "DIR/part-00000.jsonl.gz".
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with = "bulk_dir")]
+    pub(crate) output_dir: Option<PathBuf>,
+
+    /// Partition records in --output-dir into Hive-style directories by the comma-separated keys,
+    /// e.g. "elb,date,hour" into "DIR/elb=.../dt=2024-05-28/hr=13/part-00000.jsonl.gz".
+    #[arg(
+        value_enum,
+        long,
+        value_name = "KEYS",
+        value_delimiter = ',',
+        requires = "output_dir"
+    )]
+    pub(crate) partition_by: Vec<PartitionKey>,
+
+    /// Size of each file in --output-dir after compression, beyond which the next part is
+    /// started.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "128MiB")]
+    pub(crate) part_size: u64,
+
+    /// Write records into FILE instead of stdout.
+    #[arg(
+        short,
+        long = "output",
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        conflicts_with_all = ["bulk_dir", "output_dir"],
+        required_if_eq("format", "sqlite")
+    )]
+    pub(crate) output_file: Option<PathBuf>,
+
+    /// Compression of the output, which is implied by the extension of --output if omitted, e.g.
+    /// "out.jsonl.gz" or "out.jsonl.zst". Blocks of the output are compressed in parallel.
+    #[arg(value_enum, long, conflicts_with_all = ["bulk_dir", "output_dir"])]
+    pub(crate) compress: Option<Compress>,
+
+    /// Compression level, from 0 to 9 for gzip (default 6) and from 1 to 22 for zstd (default 3).
+    #[arg(long, value_name = "LEVEL")]
+    pub(crate) compress_level: Option<u32>,
+}
+
+/// Output format of each record
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) enum Format {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// Canonical log line of the load balancer, as AWS writes it
+    Raw,
+    /// Combined Log Format of Apache and Nginx
+    Combined,
+    /// Elastic Common Schema, one JSON document per line
+    Ecs,
+    /// OTLP/JSON of OpenTelemetry, one ResourceLogs per line
+    OtlpJson,
+    /// NDJSON for the bulk API of Elasticsearch and OpenSearch, an action line followed by the
+    /// JSON of each record
+    EsBulk,
+    /// Rows of a typed table, `alb` or `classic_lb`, in the SQLite database at --output
+    Sqlite,
+}
+
+impl Format {
+    /// Extension of the files which records are written into
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Format::Raw | Format::Combined => "log",
+            Format::Json | Format::Ecs | Format::OtlpJson | Format::EsBulk => "jsonl",
+            Format::Sqlite => "db",
+        }
+    }
+
+    /// Whether logs must be converted into `Record`s, since the format maps fields by name
+    pub(crate) fn needs_record(self) -> bool {
+        matches!(self, Format::Ecs | Format::OtlpJson | Format::Sqlite)
+    }
+}
+
+/// Serializes a parsed log as is.
+pub(crate) fn render_log<T: LBLogParser>(
+    log: &T::Log<'_>,
+    config: &FormatConfig,
+) -> Result<String> {
+    match config.format {
+        Format::Json => Ok(serde_json::to_string(log)?),
+        Format::Sqlite => unreachable!("Rows of SQLite are built from records"),
+        Format::EsBulk => {
+            let fields = log.fields();
+            let line = raw::render(T::TEMPLATE, |name| log_field::<T>(log, &fields, name));
+            Ok(format!(
+                "{}\n{}",
+                bulk_action::<T>(&line, log, config)?,
+                serde_json::to_string(log)?
+            ))
+        }
+        format => {
+            let fields = log.fields();
+            render::<T>(|name| log_field::<T>(log, &fields, name), format, config)
+        }
+    }
+}
+
+/// Serializes a record. Log line formats have fixed fields, so derived fields are dropped, and
+/// fields which were retyped or reformatted, e.g. by `--expand-lists` or `--tz`, are rendered
+/// from the original log. Fields which were unescaped are escaped again, and fields which were
+/// removed, e.g. by `--anonymize`, are rendered as `-`.
+pub(crate) fn render_record<T: LBLogParser>(
+    log: &T::Log<'_>,
+    record: &Record,
+    decoding: Decoding,
+    config: &FormatConfig,
+) -> Result<String> {
+    let fields = log.fields();
+    let field = |name: &str| {
+        if !T::FIELDS.contains(&name) {
+            return log_field::<T>(log, &fields, name);
+        }
+        let str = match record.get(name)? {
+            Value::Null => return None,
+            Value::String(_) if TIME_FIELDS.contains(&name) => {
+                return log_field::<T>(log, &fields, name);
+            }
+            Value::String(s) if decoding.unescape && ESCAPED_FIELDS.contains(&name) => {
+                escape(s, T::TYPE).into_owned()
+            }
+            Value::String(s) => s.clone(),
+            _ => return log_field::<T>(log, &fields, name),
+        };
+        Some(Cow::Owned(str.into_bytes()))
+    };
+    match config.format {
+        Format::Json => Ok(serde_json::to_string(record)?),
+        Format::Sqlite => unreachable!("Rows of SQLite are built by row_of"),
+        Format::Ecs => Ok(serde_json::to_string(&ecs::render(record))?),
+        Format::OtlpJson => {
+            let body = render::<T>(field, Format::Raw, config)?;
+            Ok(serde_json::to_string(&otlp::render(record, body))?)
+        }
+        Format::EsBulk => Ok(format!(
+            "{}\n{}",
+            bulk_action::<T>(&raw::render(T::TEMPLATE, field), log, config)?,
+            serde_json::to_string(record)?
+        )),
+        format => render::<T>(field, format, config),
+    }
+}
+
+/// Raw bytes of a field of the log, or of a filler in `LBLogParser::TEMPLATE`
+fn log_field<'a, T: LBLogParser>(
+    log: &'a T::Log<'_>,
+    fields: &[Option<&'a [u8]>],
+    name: &str,
+) -> Option<Cow<'a, [u8]>> {
+    match T::FIELDS.iter().position(|f| *f == name) {
+        Some(idx) => fields[idx].map(Cow::Borrowed),
+        None => log.filler(name).map(Cow::Borrowed),
+    }
+}
+
+/// Action line of the bulk API for a log. `_id` is derived from `line`, the log line rendered back
+/// from the record, which is the original line unless the record is anonymized. So it does not
+/// change with the other options, while it never reveals what is anonymized.
+fn bulk_action<T: LBLogParser>(
+    line: &[u8],
+    log: &T::Log<'_>,
+    config: &FormatConfig,
+) -> Result<String> {
+    let fields = log.fields();
+    let time = log_field::<T>(log, &fields, "time").unwrap_or_default();
+    let index = config
+        .index
+        .as_deref()
+        .expect("--index is required by --format es-bulk");
+    es_bulk::action(line, &String::from_utf8_lossy(&time), index)
+}
+
+/// Renders a log line from its fields, each of which is looked up by name in the syntax of the
+/// original log line.
+fn render<'a, T: LBLogParser>(
+    field: impl Fn(&str) -> Option<Cow<'a, [u8]>>,
+    format: Format,
+    config: &FormatConfig,
+) -> Result<String> {
+    let line = match format {
+        Format::Json | Format::Ecs | Format::OtlpJson | Format::EsBulk | Format::Sqlite => {
+            unreachable!("JSON is serialized by serde")
+        }
+        Format::Raw => raw::render(T::TEMPLATE, field),
+        Format::Combined => combined::render(
+            field,
+            T::TYPE,
+            config.combined_status,
+            config.combined_latency,
+        ),
+    };
+    String::from_utf8(line).context("Log contains invalid UTF-8 characters, see --invalid-utf8")
+}
+
+/// Sum of the processing times in seconds, or `None` if any of them is -1, i.e. the request did
+/// not reach the phase
+pub(crate) fn processing_time<S: AsRef<str>>(field: impl Fn(&str) -> Option<S>) -> Option<f64> {
+    ["request", "target", "backend", "response"]
+        .iter()
+        .filter_map(|phase| field(&format!("{phase}_processing_time")))
+        .map(|time| {
+            time.as_ref()
+                .parse::<f64>()
+                .ok()
+                .filter(|time| *time >= 0.0)
+        })
+        .sum()
+}
+
+fn non_empty(s: &str) -> Option<Value> {
+    (!s.is_empty()).then(|| Value::from(s))
+}
+
+/// Fields of a record which are yet to be mapped into another schema. Fields which are `-` are
+/// regarded as missing.
+struct Fields<'a> {
+    record: &'a Record,
+    taken: Vec<&'static str>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(record: &'a Record) -> Self {
+        Self {
+            record,
+            taken: vec![],
+        }
+    }
+
+    fn peek(&self, name: &str) -> Option<&'a str> {
+        self.record.get_str(name).filter(|s| *s != "-")
+    }
+
+    fn take(&mut self, name: &'static str) -> Option<&'a str> {
+        self.taken.push(name);
+        self.peek(name)
+    }
+
+    /// Takes the first of the fields which exists, e.g. the target of ALB or the backend of
+    /// Classic LB
+    fn take_any(&mut self, names: &[&'static str]) -> Option<&'a str> {
+        names.iter().find_map(|name| self.take(name))
+    }
+
+    fn take_int(&mut self, name: &'static str) -> Option<Value> {
+        self.take(name)?.parse::<i64>().ok().map(Value::from)
+    }
+
+    /// Fields which were not taken
+    fn rest(&self) -> impl Iterator<Item = (&'static str, &'a Value)> {
+        self.record.iter().filter(|(name, value)| {
+            !self.taken.contains(name) && !value.is_null() && value.as_str() != Some("-")
+        })
+    }
+}
@@ -0,0 +1,161 @@
+use std::borrow::Cow;
+use std::io::Write;
+
+use clap::ValueEnum;
+use jiff::Timestamp;
+use jiff::tz::TimeZone;
+
+use super::processing_time;
+use crate::Type;
+use crate::enrich::url;
+use crate::unescape::{escape, unescape};
+
+/// Which status code is written into Combined Log Format
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) enum StatusCode {
+    /// Status code of the response from the load balancer
+    #[default]
+    Elb,
+    /// Status code of the response from the target, which is "backend" for Classic LB
+    Target,
+}
+
+/// Renders a log line of Combined Log Format, e.g.
+/// `1.2.3.4 - - [11/Jan/2020:01:11:10 +0000] "GET /path?a=b HTTP/2.0" 200 488 "-" "curl/8.0"`
+///
+/// The request has the path and query of the URL only, as Apache and Nginx log it. Time is in UTC,
+/// and the referer is always `-` since load balancers do not log it.
+pub(super) fn render<'a>(
+    field: impl Fn(&str) -> Option<Cow<'a, [u8]>>,
+    r#type: Type,
+    status_code: StatusCode,
+    latency: bool,
+) -> Vec<u8> {
+    let str = |name: &str| field(name).map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+    let or_dash = |name: &str| str(name).unwrap_or_else(|| "-".to_owned());
+
+    let time = str("time")
+        .and_then(|time| time.parse::<Timestamp>().ok())
+        .map_or_else(
+            || "-".to_owned(),
+            |time| {
+                time.to_zoned(TimeZone::UTC)
+                    .strftime("%d/%b/%Y:%H:%M:%S %z")
+                    .to_string()
+            },
+        );
+    let request = match (str("http_method"), str("url")) {
+        (Some(method), Some(url)) if method != "-" => {
+            let mut request = format!("{method} {}", origin_form(&url));
+            let version = str("http_version").filter(|v| !v.trim().is_empty() && v != "-");
+            if let Some(version) = version {
+                request.push(' ');
+                request.push_str(version.trim());
+            }
+            request
+        }
+        _ => "-".to_owned(),
+    };
+    let status = match status_code {
+        StatusCode::Elb => or_dash("elb_status_code"),
+        StatusCode::Target => str("target_status_code")
+            .or_else(|| str("backend_status_code"))
+            .unwrap_or_else(|| "-".to_owned()),
+    };
+    // User agents are escaped as Apache does, which is how ALB does
+    let user_agent = str("user_agent").map_or_else(
+        || "-".to_owned(),
+        |ua| {
+            escape(
+                &String::from_utf8_lossy(&unescape(ua.as_bytes(), r#type)),
+                Type::Alb,
+            )
+            .into_owned()
+        },
+    );
+
+    let mut out = Vec::new();
+    write!(
+        out,
+        r#"{} - - [{time}] "{request}" {status} {} "-" "{user_agent}""#,
+        or_dash("client_ip"),
+        or_dash("sent_bytes"),
+    )
+    .unwrap();
+    if latency {
+        match processing_time(str) {
+            Some(time) => write!(out, " {time:.3}").unwrap(),
+            None => out.extend_from_slice(b" -"),
+        }
+    }
+    out
+}
+
+/// Strips the scheme and the authority from an absolute URL, e.g. `https://example.com:443/a?b`
+/// into `/a?b`
+fn origin_form(url: &str) -> Cow<'_, str> {
+    let split = url::split(url);
+    if split.scheme.is_none() {
+        return Cow::Borrowed(url);
+    }
+    let path = split.path.unwrap_or("/");
+    match split.query {
+        Some(query) => Cow::Owned(format!("{path}?{query}")),
+        None => Cow::Borrowed(path),
+    }
+}
+
+#[test]
+fn test_combined_log_format() {
+    let fields = [
+        ("time", "2020-01-11T01:11:10.111111Z"),
+        ("client_ip", "1.123.123.123"),
+        ("request_processing_time", "0.000"),
+        ("target_processing_time", "0.159"),
+        ("response_processing_time", "0.001"),
+        ("elb_status_code", "502"),
+        ("target_status_code", "-"),
+        ("sent_bytes", "488"),
+        ("http_method", "GET"),
+        (
+            "url",
+            "https://example.com:443/very/good/route?some=pArameter",
+        ),
+        ("http_version", "HTTP/2.0"),
+        ("user_agent", r#"\xEC\x97\x90 \"hi\""#),
+    ];
+    let field = |name: &str| {
+        let (_, value) = fields.iter().find(|(n, _)| *n == name)?;
+        Some(Cow::Borrowed(value.as_bytes()))
+    };
+    let t = |status_code, latency, expected: &str| {
+        assert_eq!(
+            String::from_utf8(render(field, Type::Alb, status_code, latency)).unwrap(),
+            expected
+        );
+    };
+
+    t(
+        StatusCode::Elb,
+        false,
+        r#"1.123.123.123 - - [11/Jan/2020:01:11:10 +0000] "GET /very/good/route?some=pArameter HTTP/2.0" 502 488 "-" "\xEC\x97\x90 \"hi\"""#,
+    );
+    t(
+        StatusCode::Target,
+        true,
+        r#"1.123.123.123 - - [11/Jan/2020:01:11:10 +0000] "GET /very/good/route?some=pArameter HTTP/2.0" - 488 "-" "\xEC\x97\x90 \"hi\"" 0.160"#,
+    );
+
+    assert_eq!(origin_form("https://example.com:443"), "/");
+    assert_eq!(origin_form("http://example.com?a=b"), "/?a=b");
+    assert_eq!(origin_form("-"), "-");
+    assert_eq!(origin_form("/a?next=https://x"), "/a?next=https://x");
+
+    // A missing HTTP version is left out of the request line
+    let field = |name: &str| match name {
+        "http_version" => Some(Cow::Borrowed(&b"-"[..])),
+        name => field(name),
+    };
+    let line = String::from_utf8(render(field, Type::Alb, StatusCode::Elb, false)).unwrap();
+    assert!(line.contains(r#" "GET /very/good/route?some=pArameter" 502 "#));
+}
@@ -0,0 +1,182 @@
+use serde_json::{Map, Value, json};
+
+use super::{Fields, non_empty, processing_time};
+use crate::enrich::{arn, lists, url};
+use crate::record::Record;
+
+/// Version of ECS which the fields follow
+const ECS_VERSION: &str = "8.11.0";
+
+/// Renders a record as an Elastic Common Schema document, e.g.
+/// `{"@timestamp": "...", "source": {"ip": "1.2.3.4", "port": 12345}, ...}`
+///
+/// Fields without an ECS counterpart, including derived fields, are kept under `aws.elb` as in
+/// the AWS module of Filebeat. Fields which are `-` are omitted.
+pub(super) fn render(record: &Record) -> Value {
+    let mut fields = Fields::new(record);
+    let mut doc = Map::new();
+    let mut put = |name: &str, value: Option<Value>| {
+        if let Some(value) = value {
+            insert(&mut doc, name, value);
+        }
+    };
+
+    put("@timestamp", fields.take("time").map(Value::from));
+    put("ecs.version", Some(Value::from(ECS_VERSION)));
+    put("event.kind", Some(Value::from("event")));
+    put("event.category", Some(json!(["web"])));
+    put("event.type", Some(json!(["access"])));
+    put(
+        "event.start",
+        fields.take("request_creation_time").map(Value::from),
+    );
+    put(
+        "event.duration",
+        processing_time(|name| fields.peek(name))
+            .map(|secs| Value::from((secs * 1e9).round() as i64)),
+    );
+
+    put("cloud.provider", Some(Value::from("aws")));
+    if let Some(arn) = fields.peek("target_group_arn").and_then(arn::parse) {
+        put("cloud.region", non_empty(arn.region));
+        put("cloud.account.id", non_empty(arn.account));
+    }
+    put("aws.elb.name", fields.take("elb").map(Value::from));
+
+    put("source.ip", fields.take("client_ip").map(Value::from));
+    put("source.port", fields.take_int("client_port"));
+    if let Some(ip_port) = fields.take_any(&["target_ip_port", "backend_ip_port"]) {
+        let (ip, port) = lists::split_ip_port(ip_port);
+        put("destination.ip", Some(Value::from(ip)));
+        put("destination.port", port.map(Value::from));
+    }
+
+    put(
+        "http.request.method",
+        fields.take("http_method").map(Value::from),
+    );
+    put(
+        "http.version",
+        fields
+            .take("http_version")
+            .map(|v| Value::from(v.trim().trim_start_matches("HTTP/"))),
+    );
+    put("http.request.bytes", fields.take_int("received_bytes"));
+    put("http.response.bytes", fields.take_int("sent_bytes"));
+    let status_code = fields.take_int("elb_status_code");
+    let outcome = match status_code.as_ref().and_then(Value::as_u64) {
+        Some(400..) => "failure",
+        Some(_) => "success",
+        None => "unknown",
+    };
+    put("http.response.status_code", status_code);
+    put("event.outcome", Some(Value::from(outcome)));
+
+    if let Some(full) = fields.take("url") {
+        let url = url::split(full);
+        put("url.original", Some(Value::from(full)));
+        put("url.full", Some(Value::from(full)));
+        put("url.scheme", url.scheme.map(Value::from));
+        put("url.domain", url.hostname().map(Value::from));
+        put(
+            "url.port",
+            url.port
+                .and_then(|p| p.parse::<u16>().ok())
+                .map(Value::from),
+        );
+        put("url.path", url.path.map(Value::from));
+        put("url.query", url.query.map(Value::from));
+    }
+    put(
+        "user_agent.original",
+        fields.take("user_agent").map(Value::from),
+    );
+
+    put("tls.cipher", fields.take("ssl_cipher").map(Value::from));
+    if let Some(protocol) = fields.take("ssl_protocol") {
+        put(
+            "tls.version",
+            Some(Value::from(protocol.trim_start_matches("TLSv"))),
+        );
+        put("tls.version_protocol", Some(Value::from("tls")));
+    }
+    put(
+        "tls.client.server_name",
+        fields.take("domain_name").map(Value::from),
+    );
+    put("error.code", fields.take("error_reason").map(Value::from));
+
+    for (name, value) in fields.rest() {
+        put(&format!("aws.elb.{name}"), Some(value.clone()));
+    }
+    Value::Object(doc)
+}
+
+/// Inserts a value at a dotted path, e.g. `source.ip` into `{"source": {"ip": ...}}`
+fn insert(doc: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((parent, rest)) => {
+            let child = doc
+                .entry(parent)
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = child {
+                insert(child, rest, value);
+            }
+        }
+        _ => {
+            doc.insert(path.to_owned(), value);
+        }
+    }
+}
+
+#[test]
+fn test_ecs() {
+    let mut record = Record::default();
+    for (name, value) in [
+        ("time", "2020-01-11T01:11:10.111111Z"),
+        ("elb", "app/myalb/0123456789abcdef"),
+        ("client_ip", "1.123.123.123"),
+        ("client_port", "12345"),
+        ("target_ip_port", "-"),
+        ("request_processing_time", "-1"),
+        ("target_processing_time", "-1"),
+        ("response_processing_time", "-1"),
+        ("elb_status_code", "503"),
+        ("http_method", "GET"),
+        ("url", "https://example.com:443/path?a=b"),
+        ("ssl_protocol", "TLSv1.3"),
+    ] {
+        record.insert(name, value);
+    }
+    record.insert("ua_family", "curl");
+
+    let doc = render(&record);
+    assert_eq!(doc["@timestamp"], "2020-01-11T01:11:10.111111Z");
+    assert_eq!(
+        doc["source"],
+        json!({ "ip": "1.123.123.123", "port": 12345 })
+    );
+    assert_eq!(doc["destination"], Value::Null);
+    assert_eq!(doc["event"]["duration"], Value::Null);
+    assert_eq!(doc["event"]["outcome"], "failure");
+    assert_eq!(doc["http"]["response"]["status_code"], 503);
+    assert_eq!(doc["url"]["full"], "https://example.com:443/path?a=b");
+    assert_eq!(doc["url"]["port"], 443);
+    assert_eq!(doc["tls"]["version"], "1.3");
+    assert_eq!(doc["aws"]["elb"]["name"], "app/myalb/0123456789abcdef");
+    assert_eq!(doc["aws"]["elb"]["ua_family"], "curl");
+
+    // IPv6 addresses are not enclosed in brackets, which `ip` fields do not accept
+    record.insert("target_ip_port", "[2001:db8::1]:8080");
+    record.insert("url", "https://[2001:db8::2]:443/");
+    let doc = render(&record);
+    assert_eq!(
+        doc["destination"],
+        json!({ "ip": "2001:db8::1", "port": 8080 })
+    );
+    assert_eq!(doc["url"]["domain"], "2001:db8::2");
+    assert_eq!(doc["url"]["port"], 443);
+}
@@ -0,0 +1,43 @@
+use anyhow::{Context, Result};
+use jiff::fmt::strtime;
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, Zoned};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// Validates an index name pattern, which may contain strftime specifiers.
+pub(crate) fn parse_index(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("Index name must not be empty".to_owned());
+    }
+    // Reject invalid patterns now, rather than failing on every record
+    strtime::format(s, &Zoned::now()).map_err(|e| e.to_string())?;
+    Ok(s.to_owned())
+}
+
+/// Action line of the bulk API which indexes the following document, e.g.
+/// `{"index":{"_index":"alb-2020.01.11","_id":"..."}}`
+///
+/// The index is named after the time of the log in UTC. `_id` is derived from the log line, so
+/// that ingesting the same logs again overwrites the same documents.
+pub(super) fn action(line: &[u8], time: &str, index: &str) -> Result<String> {
+    let time: Timestamp = time
+        .parse()
+        .with_context(|| format!("Invalid time of a log, {time}"))?;
+    let index = strtime::format(index, &time.to_zoned(TimeZone::UTC))?;
+    let digest = Sha256::digest(line);
+    let id: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+    Ok(json!({ "index": { "_index": index, "_id": id } }).to_string())
+}
+
+#[test]
+fn test_action() {
+    let line = b"h2 2020-01-11T01:11:10.111111Z app/myalb/0123456789abcdef ...";
+    let action = action(line, "2020-01-11T23:59:59.999999Z", "alb-%Y.%m.%d").unwrap();
+    assert_eq!(
+        action,
+        r#"{"index":{"_id":"1587998791a03a5c3b5dd8118208e635","_index":"alb-2020.01.11"}}"#
+    );
+    assert!(parse_index("alb-%Y.%").is_err());
+    assert!(parse_index("").is_err());
+}
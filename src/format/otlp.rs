@@ -0,0 +1,193 @@
+use jiff::Timestamp;
+use serde_json::{Value, json};
+
+use super::{Fields, non_empty};
+use crate::enrich::{arn, trace, url};
+use crate::record::Record;
+
+/// Renders a record as an OTLP/JSON export request of a single log record, e.g.
+/// `{"resourceLogs": [{"resource": {...}, "scopeLogs": [{"scope": {...}, "logRecords": [...]}]}]}`
+///
+/// Identity of the load balancer becomes the resource attributes, and the other fields become
+/// attributes of the log record, named after the semantic conventions of OpenTelemetry where
+/// there is one, or under `aws.elb` otherwise. `body` is the log line.
+pub(super) fn render(record: &Record, body: String) -> Value {
+    let mut fields = Fields::new(record);
+
+    let mut resource = vec![
+        attribute("cloud.provider", Some(Value::from("aws"))),
+        attribute("cloud.platform", Some(Value::from("aws_elb"))),
+    ];
+    if let Some(arn) = fields.peek("target_group_arn").and_then(arn::parse) {
+        resource.push(attribute("cloud.region", non_empty(arn.region)));
+        resource.push(attribute("cloud.account.id", non_empty(arn.account)));
+    }
+    if let Some(elb) = fields.take("elb") {
+        let [_, name, _] = arn::split_elb(elb);
+        resource.push(attribute("service.name", name.map(Value::from)));
+        resource.push(attribute("aws.elb.name", Some(Value::from(elb))));
+    }
+
+    let time = fields
+        .take("time")
+        .and_then(|time| time.parse::<Timestamp>().ok());
+    let trace_id = fields
+        .peek("trace_id")
+        .and_then(|trace_id| trace::parse(trace_id).root)
+        .and_then(trace::split_root)
+        .map(|(_, w3c_id)| w3c_id);
+    let status_code = fields.take_int("elb_status_code");
+    let (severity_number, severity_text) = match status_code.as_ref().and_then(Value::as_u64) {
+        Some(500..) => (17, "ERROR"),
+        Some(400..) => (13, "WARN"),
+        _ => (9, "INFO"),
+    };
+
+    let mut attributes = vec![
+        attribute("client.address", fields.take("client_ip").map(Value::from)),
+        attribute("client.port", fields.take_int("client_port")),
+        attribute(
+            "http.request.method",
+            fields.take("http_method").map(Value::from),
+        ),
+        attribute("http.response.status_code", status_code),
+        attribute("http.request.size", fields.take_int("received_bytes")),
+        attribute("http.response.size", fields.take_int("sent_bytes")),
+        attribute(
+            "network.protocol.version",
+            fields
+                .take("http_version")
+                .map(|v| Value::from(v.trim().trim_start_matches("HTTP/"))),
+        ),
+        attribute(
+            "user_agent.original",
+            fields.take("user_agent").map(Value::from),
+        ),
+        attribute("tls.cipher", fields.take("ssl_cipher").map(Value::from)),
+        attribute(
+            "tls.protocol.version",
+            fields
+                .take("ssl_protocol")
+                .map(|p| Value::from(p.trim_start_matches("TLSv"))),
+        ),
+        attribute(
+            "tls.client.server_name",
+            fields.take("domain_name").map(Value::from),
+        ),
+        attribute("error.type", fields.take("error_reason").map(Value::from)),
+    ];
+    if let Some(full) = fields.take("url") {
+        let url = url::split(full);
+        attributes.push(attribute("url.full", Some(Value::from(full))));
+        attributes.push(attribute("url.scheme", url.scheme.map(Value::from)));
+        attributes.push(attribute("server.address", url.hostname().map(Value::from)));
+        attributes.push(attribute(
+            "server.port",
+            url.port
+                .and_then(|p| p.parse::<i64>().ok())
+                .map(Value::from),
+        ));
+        attributes.push(attribute("url.path", url.path.map(Value::from)));
+        attributes.push(attribute("url.query", url.query.map(Value::from)));
+    }
+    for (name, value) in fields.rest() {
+        attributes.push(attribute(&format!("aws.elb.{name}"), Some(value.clone())));
+    }
+
+    let mut log_record = json!({
+        "severityNumber": severity_number,
+        "severityText": severity_text,
+        "body": { "stringValue": body },
+        "attributes": attributes.into_iter().flatten().collect::<Vec<_>>(),
+    });
+    if let Some(time) = time {
+        log_record["timeUnixNano"] = Value::from(time.as_nanosecond().to_string());
+    }
+    if let Some(trace_id) = trace_id {
+        log_record["traceId"] = Value::from(trace_id);
+    }
+    json!({
+        "resourceLogs": [{
+            "resource": { "attributes": resource.into_iter().flatten().collect::<Vec<_>>() },
+            "scopeLogs": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "logRecords": [log_record],
+            }],
+        }],
+    })
+}
+
+/// Key-value pair of OTLP, or `None` if the value is missing
+fn attribute(key: &str, value: Option<Value>) -> Option<Value> {
+    Some(json!({ "key": key, "value": any_value(&value?) }))
+}
+
+/// Converts into an AnyValue of OTLP/JSON, in which 64-bit integers are strings
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({}),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n.as_f64() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Array(values) => {
+            json!({ "arrayValue": { "values": values.iter().map(any_value).collect::<Vec<_>>() } })
+        }
+        Value::Object(map) => {
+            let values: Vec<_> = map
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+                .collect();
+            json!({ "kvlistValue": { "values": values } })
+        }
+    }
+}
+
+#[test]
+fn test_otlp() {
+    let mut record = Record::default();
+    for (name, value) in [
+        ("time", "2020-01-11T01:11:10.111111Z"),
+        ("elb", "app/myalb/0123456789abcdef"),
+        ("elb_status_code", "502"),
+        ("url", "https://[2001:db8::2]:443/"),
+        ("trace_id", "Root=1-67891233-abcdef012345678912345678"),
+        (
+            "target_group_arn",
+            "arn:aws:elasticloadbalancing:us-east-1:123456789012:targetgroup/tg/73e2d6bc24d8a067",
+        ),
+    ] {
+        record.insert(name, value);
+    }
+    record.insert("trace_sampled", true);
+
+    let doc = render(&record, "line".to_owned());
+    let resource_logs = &doc["resourceLogs"][0];
+    assert!(
+        resource_logs["resource"]["attributes"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "key": "service.name", "value": { "stringValue": "myalb" } }))
+    );
+    let log_record = &resource_logs["scopeLogs"][0]["logRecords"][0];
+    assert_eq!(log_record["timeUnixNano"], "1578705070111111000");
+    assert_eq!(log_record["severityText"], "ERROR");
+    assert_eq!(log_record["traceId"], "67891233abcdef012345678912345678");
+    assert_eq!(log_record["body"], json!({ "stringValue": "line" }));
+    let attributes = log_record["attributes"].as_array().unwrap();
+    assert!(attributes.contains(&json!({
+        "key": "http.response.status_code",
+        "value": { "intValue": "502" },
+    })));
+    assert!(attributes.contains(&json!({
+        "key": "server.address",
+        "value": { "stringValue": "2001:db8::2" },
+    })));
+    assert!(attributes.contains(&json!({
+        "key": "aws.elb.trace_sampled",
+        "value": { "boolValue": true },
+    })));
+}
@@ -0,0 +1,88 @@
+use std::borrow::Cow;
+
+/// Renders a log line from `LBLogParser::TEMPLATE`. A missing field is rendered as `-`, unless it
+/// is in an optional `[...]` part, which is omitted then.
+pub(super) fn render<'a>(template: &str, field: impl Fn(&str) -> Option<Cow<'a, [u8]>>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut rest = template;
+    while !rest.is_empty() {
+        if let Some(optional) = rest.strip_prefix('[') {
+            let (optional, after) = optional
+                .split_once(']')
+                .expect("Optional part of a template must be closed");
+            let mut part = Vec::new();
+            if render_fields(optional, &field, &mut part) {
+                out.extend_from_slice(&part);
+            }
+            rest = after;
+        } else {
+            let end = rest.find('[').unwrap_or(rest.len());
+            render_fields(&rest[..end], &field, &mut out);
+            rest = &rest[end..];
+        }
+    }
+    out
+}
+
+/// Returns whether every field in `template` exists.
+fn render_fields<'a>(
+    template: &str,
+    field: &impl Fn(&str) -> Option<Cow<'a, [u8]>>,
+    out: &mut Vec<u8>,
+) -> bool {
+    let mut exists = true;
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.extend_from_slice(&rest.as_bytes()[..start]);
+        let (name, after) = rest[start + 1..]
+            .split_once('}')
+            .expect("Field of a template must be closed");
+        match field(name) {
+            Some(bytes) => out.extend_from_slice(&bytes),
+            None => {
+                exists = false;
+                out.push(b'-');
+            }
+        }
+        rest = after;
+    }
+    out.extend_from_slice(rest.as_bytes());
+    exists
+}
+
+#[test]
+fn test_round_trip() {
+    use serde_json::{Value, json};
+
+    use super::{Format, FormatConfig, render_log, render_record};
+    use crate::alb::LogParser;
+    use crate::parse::LBLogParser;
+    use crate::record::{Decoding, InvalidUtf8, Record};
+
+    // Undocumented spaces after http_version and before domain_name are rendered back
+    let line = br#"http 2020-01-01T22:22:22.222222Z app/myalb/0123456789abcdef 123.123.123.123:12345 10.0.10.0:80 0.001 0.007 0.000 404 404 19 997 "GET http://example.com:80/?q=\"x\" HTTP/1.0 " "curl/8.0" - - arn:aws:elasticloadbalancing:ap-northeast-2:0123456789:targetgroup/mytg/0123456789abcdef "Root=1-abcd0123-0123456789abcdef01234567" " example.com" "-" 0 2020-01-01T22:22:22.222000Z "waf,forward" "-" "-" "10.0.10.0:80" "404" "-" "-""#;
+    let log = LogParser::new().parse(line).unwrap();
+    let config = FormatConfig {
+        format: Format::Raw,
+        ..Default::default()
+    };
+    let rendered = render_log::<LogParser>(&log, &config).unwrap();
+    assert_eq!(rendered.as_bytes(), line);
+
+    // Unescaped fields are escaped again, and retyped or reformatted fields are rendered from the
+    // log as is
+    let decoding = Decoding {
+        invalid_utf8: InvalidUtf8::Error,
+        unescape: true,
+    };
+    let mut record = Record::from_log::<LogParser>(&log, decoding).unwrap();
+    assert_eq!(
+        record.get_str("url"),
+        Some(r#"http://example.com:80/?q="x""#)
+    );
+    record.insert("actions_executed", json!(["waf", "forward"]));
+    record.insert("time", Value::from(1577917342));
+    record.insert("request_creation_time", "2020-01-02T07:22:22.222+09:00");
+    let rendered = render_record::<LogParser>(&log, &record, decoding, &config).unwrap();
+    assert_eq!(rendered.as_bytes(), line);
+}
@@ -1,41 +1,63 @@
 mod alb;
+mod budget;
 mod classic_lb;
+mod diagnose;
+mod enrich;
+mod format;
+mod metrics;
+mod output;
 mod parse;
+mod query;
+mod record;
+mod rejects;
+mod unescape;
 
 use std::fs::{File, metadata};
-use std::io::{BufRead, BufReader, IsTerminal, Write, stderr, stdin, stdout};
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Write, stderr, stdin, stdout};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 
-use anyhow::{Result, bail};
+use anyhow::{Error, Result, bail};
 use clap::{CommandFactory, Parser, Subcommand, ValueEnum, builder::ValueHint};
 use clap_complete::{Shell, generate};
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{Receiver, SendError, Sender, unbounded};
 use flate2::read::MultiGzDecoder;
 use walkdir::{DirEntry, WalkDir};
 
 use crate::alb::LogParser as ALBLogParser;
+use crate::budget::{ErrorBudget, ErrorBudgetExceeded, parse_rate};
 use crate::classic_lb::LogParser as ClassicLBLogParser;
-use crate::parse::{LBLogParser, ParseLogError};
+use crate::enrich::{Caches, EnrichConfig, Enricher};
+use crate::format::{Format, FormatConfig, render_log, render_record};
+use crate::metrics::{Aggregate, Metrics, parse_step};
+use crate::output::{Line, Output, Serialized, partition_of, row_of};
+use crate::parse::{LBLogParser, ParseLogError, check_utf8};
+use crate::record::{Decoding, InvalidUtf8, Record};
+use crate::rejects::Rejects;
 
 #[derive(Parser)]
 #[command(
     about,
     version,
     arg_required_else_help = true,
-    args_conflicts_with_subcommands = true
+    args_conflicts_with_subcommands = true,
+    after_help = EXIT_STATUS_HELP
 )]
 struct Args {
     /// Type of load balancer.
     #[arg(value_enum, short, long, default_value_t = Type::Alb)]
     r#type: Type,
 
-    #[command(flatten)]
-    config: Config,
-
     /// Path of directory containing load balancer logs. To read from stdin, use "-".
     #[arg(required = true, value_hint = ValueHint::DirPath, allow_hyphen_values = true)]
     path: Option<String>,
 
+    #[command(flatten)]
+    config: Config,
+
     /// Subcommands
     #[command(subcommand)]
     command: Option<Commands>,
@@ -47,11 +69,66 @@ enum Type {
     ClassicLb,
 }
 
-#[derive(Parser, Clone, Copy)]
+#[derive(Parser, Clone, Default)]
 struct Config {
+    #[command(flatten)]
+    errors: ErrorConfig,
+
+    #[command(flatten)]
+    decoding: DecodingConfig,
+
+    #[command(flatten)]
+    enrich: EnrichConfig,
+
+    #[command(flatten)]
+    output: FormatConfig,
+}
+
+/// Options for decoding the fields of a log into strings
+#[derive(Parser, Clone, Default)]
+struct DecodingConfig {
+    /// How to handle fields which are not valid UTF-8. Names of the altered fields are listed in
+    /// "invalid_utf8_fields".
+    #[arg(value_enum, long, value_name = "MODE", default_value_t = InvalidUtf8::Error)]
+    invalid_utf8: InvalidUtf8,
+
+    /// Decode escape sequences in url, user_agent, redirect_url and trace_id, which are "\xHH" for
+    /// ALB, "\xHHHHHHHH" for Classic LB, "\"" and "\\".
+    #[arg(long)]
+    unescape: bool,
+}
+
+/// Options for lines which failed to parse
+#[derive(Parser, Clone, Default)]
+struct ErrorConfig {
     /// Skip parsing errors.
     #[arg(long)]
     skip_parse_errors: bool,
+
+    /// Skip parsing errors, but abort once more than N lines failed to parse.
+    #[arg(long, value_name = "N")]
+    max_errors: Option<u64>,
+
+    /// Skip parsing errors, but fail if the ratio of lines which failed to parse exceeds RATE,
+    /// e.g. "0.1%" or "0.001". The ratio is checked once every line is read, so it never stops a
+    /// run early.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    max_error_rate: Option<f64>,
+
+    /// Write every rejected line to this file, along with its source file and line number. The
+    /// file is gzip compressed if the path ends with ".gz". Rejected lines are written as is, so
+    /// this cannot be used with --anonymize.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    rejects: Option<PathBuf>,
+}
+
+impl Config {
+    fn decoding(&self) -> Decoding {
+        Decoding {
+            invalid_utf8: self.decoding.invalid_utf8,
+            unescape: self.decoding.unescape,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -62,141 +139,494 @@ enum Commands {
         #[arg(value_enum)]
         shell: Shell,
     },
+
+    /// Aggregate logs into request counters and latency histograms in OpenMetrics text
+    ///
+    /// Every sample has a timestamp, so that the output can be imported with
+    /// `promtool tsdb create-blocks-from openmetrics`.
+    #[command(arg_required_else_help = true, after_help = EXIT_STATUS_HELP)]
+    Metrics {
+        /// Type of load balancer.
+        #[arg(value_enum, short, long, default_value_t = Type::Alb)]
+        r#type: Type,
+
+        /// Path of directory containing load balancer logs. To read from stdin, use "-".
+        #[arg(value_hint = ValueHint::DirPath, allow_hyphen_values = true)]
+        path: String,
+
+        /// Interval between samples, e.g. "30s", "1m" or "1h".
+        #[arg(long, value_name = "DURATION", value_parser = parse_step, default_value = "1m")]
+        step: i64,
+
+        #[command(flatten)]
+        errors: ErrorConfig,
+    },
+
+    /// Run an SQL query over logs, which are exposed as the `logs` table, and write each row of
+    /// the result as a JSON object
+    ///
+    /// Columns of `logs` are the fields of each log, `extra` for the fields added by the
+    /// Enrichment options and `file` for the path of the log file. Comparisons of `time` skip log
+    /// files which cannot contain matching logs, judging by the time in their names, unless
+    /// --time-format renders it other than rfc3339.
+    #[command(arg_required_else_help = true, after_help = EXIT_STATUS_HELP)]
+    Query {
+        /// Type of load balancer.
+        #[arg(value_enum, short, long, default_value_t = Type::Alb)]
+        r#type: Type,
+
+        /// SQLite query, e.g. "SELECT domain_name, count(*) FROM logs WHERE elb_status_code >=
+        /// 500 GROUP BY 1".
+        sql: String,
+
+        /// Path of directory containing load balancer logs.
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: String,
+
+        #[command(flatten)]
+        errors: ErrorConfig,
+
+        #[command(flatten)]
+        decoding: DecodingConfig,
+
+        #[command(flatten)]
+        enrich: Box<EnrichConfig>,
+    },
+}
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0  Every line was parsed successfully
+  1  Failure, e.g. a line failed to parse while parse errors are not skipped
+  2  Invalid command line arguments
+  3  I/O failure, e.g. a log file could not be read
+  4  Too many parse errors, see --max-errors and --max-error-rate
+  5  Partial success, some lines failed to parse and were skipped";
+
+const EXIT_IO_FAILURE: u8 = 3;
+const EXIT_TOO_MANY_ERRORS: u8 = 4;
+const EXIT_PARTIAL_SUCCESS: u8 = 5;
+
+/// Set on SIGINT or SIGTERM when writing into --output-dir, so that every thread stops and the
+/// files written so far are finished rather than truncated.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// State shared by every thread during a run
+struct Context {
+    config: Config,
+    enricher: Enricher,
+    rejects: Option<Rejects>,
+    budget: ErrorBudget,
+    /// Aggregates logs into metrics instead of writing them, for the `metrics` subcommand
+    metrics: Option<Metrics>,
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args = Args::parse();
 
-    // Handle shell completion
-    if let Some(Commands::Completion { shell }) = args.command {
-        let mut cmd = Args::command();
-        let bin_name = env!("CARGO_PKG_NAME");
-        generate(shell, &mut cmd, bin_name, &mut stdout());
-        return Ok(());
+    let result = match args.command {
+        // Handle shell completion
+        Some(Commands::Completion { shell }) => {
+            let mut cmd = Args::command();
+            let bin_name = env!("CARGO_PKG_NAME");
+            generate(shell, &mut cmd, bin_name, &mut stdout());
+            return ExitCode::SUCCESS;
+        }
+        Some(Commands::Metrics {
+            r#type,
+            path,
+            step,
+            errors,
+        }) => {
+            let config = Config {
+                errors,
+                ..Default::default()
+            };
+            run(&path, r#type, config, Some(Metrics::new(step)), None)
+        }
+        Some(Commands::Query {
+            r#type,
+            sql,
+            path,
+            errors,
+            decoding,
+            enrich,
+        }) => {
+            let config = Config {
+                errors,
+                decoding,
+                enrich: *enrich,
+                output: FormatConfig {
+                    format: Format::Sqlite,
+                    ..Default::default()
+                },
+            };
+            run(&path, r#type, config, None, Some(&sql))
+        }
+        None => {
+            // Otherwise, args.path must exist
+            let Some(path) = args.path else {
+                unreachable!()
+            };
+            run(&path, args.r#type, args.config, None, None)
+        }
+    };
+    match result {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::from(EXIT_PARTIAL_SUCCESS),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            exit_code_of(&err)
+        }
     }
+}
 
-    // Otherwise, args.path must exist
-    let Some(path) = args.path else {
-        unreachable!()
+/// Returns the number of lines which failed to parse and were skipped.
+fn run(
+    path: &str,
+    r#type: Type,
+    config: Config,
+    metrics: Option<Metrics>,
+    sql: Option<&str>,
+) -> Result<u64> {
+    let mut ctx = Context::new(config, metrics)?;
+    if sql.is_some() {
+        ctx.budget.track_sources();
+    }
+    if ctx.config.output.output_dir.is_some() || ctx.config.output.output_file.is_some() {
+        ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed))?;
+    }
+    let result = match (r#type, sql) {
+        (Type::Alb, None) => main_of::<ALBLogParser>(path, &ctx),
+        (Type::ClassicLb, None) => main_of::<ClassicLBLogParser>(path, &ctx),
+        (Type::Alb, Some(sql)) => query::run::<ALBLogParser>(sql, path, &ctx, stdout().lock()),
+        (Type::ClassicLb, Some(sql)) => {
+            query::run::<ClassicLBLogParser>(sql, path, &ctx, stdout().lock())
+        }
     };
-    match args.r#type {
-        Type::Alb => main_of::<ALBLogParser>(&path, args.config)?,
-        Type::ClassicLb => main_of::<ClassicLBLogParser>(&path, args.config)?,
+    ctx.enricher.summary();
+    if let Some(rejects) = ctx.rejects {
+        rejects.finish()?;
     }
-    Ok(())
+    result?;
+    ctx.budget.check_rate()?;
+    if let Some(metrics) = ctx.metrics {
+        let mut stdout = BufWriter::new(stdout().lock());
+        metrics.write(&mut stdout)?;
+        stdout.flush()?;
+    }
+    Ok(ctx.budget.errors())
 }
 
-fn main_of<T: LBLogParser>(path: &str, config: Config) -> Result<()> {
+fn exit_code_of(err: &Error) -> ExitCode {
+    for cause in err.chain() {
+        // A parse error without any budget is a plain failure
+        if cause.is::<ErrorBudgetExceeded>() {
+            return ExitCode::from(EXIT_TOO_MANY_ERRORS);
+        }
+        if cause.is::<std::io::Error>() || cause.is::<walkdir::Error>() {
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    ExitCode::FAILURE
+}
+
+fn main_of<T: LBLogParser>(path: &str, ctx: &Context) -> Result<()> {
     if path != "-" {
-        walkdir::<T>(path, config)
+        walkdir::<T>(WalkDir::new(path), ctx, |rx| {
+            // Metrics are written once every log is aggregated, and no record is sent at all
+            if ctx.metrics.is_some() {
+                return Ok(());
+            }
+            let mut output = Output::create::<T>(&ctx.config.output)?;
+            while let Ok(line) = rx.recv() {
+                output.write(&line)?;
+            }
+            output.finish()
+        })
+    } else if let Some(metrics) = &ctx.metrics {
+        let mut aggregate = Aggregate::default();
+        for_each_parsed_lines::<T>(stdin().lock(), "-", ctx, |log| {
+            aggregate.add::<T>(log, metrics.step);
+            Ok(())
+        })?;
+        metrics.merge(aggregate);
+        Ok(())
     } else {
         let stdin = stdin().lock();
-        let mut stdout = stdout().lock();
-        for_each_parsed_lines::<T>(stdin, config, |log| {
-            serde_json::to_writer(&mut stdout, log)?;
-            stdout.write_all(b"\n")?;
-            Ok(())
+        let mut output = Output::create::<T>(&ctx.config.output)?;
+        let source: Arc<str> = "-".into();
+        let mut caches = Caches::default();
+        for_each_parsed_lines::<T>(stdin, "-", ctx, |log| {
+            output.write(&serialize::<T>(log, &source, ctx, &mut caches)?)
+        })?;
+        output.finish()
+    }
+}
+
+impl Context {
+    fn new(config: Config, metrics: Option<Metrics>) -> Result<Self> {
+        config.enrich.check_format(config.output.format)?;
+        let enricher = Enricher::new(&config.enrich)?;
+        let errors = &config.errors;
+        if errors.rejects.is_some() && enricher.anonymizes() {
+            bail!(
+                "--rejects cannot be used with --anonymize, since rejected lines are written as is"
+            );
+        }
+        Ok(Context {
+            rejects: errors.rejects.as_deref().map(Rejects::create).transpose()?,
+            budget: ErrorBudget::new(
+                errors.skip_parse_errors,
+                errors.max_errors,
+                errors.max_error_rate,
+            ),
+            metrics,
+            enricher,
+            config,
         })
     }
+
+    /// Whether logs must be converted into `Record`s instead of being serialized as is
+    fn needs_record(&self) -> bool {
+        self.config.decoding.invalid_utf8 != InvalidUtf8::Error
+            || self.config.decoding.unescape
+            || !self.enricher.is_empty()
+            || self.config.output.format.needs_record()
+    }
 }
 
-fn walkdir<T: LBLogParser>(path: &str, config: Config) -> Result<()> {
+fn serialize<T: LBLogParser>(
+    log: &T::Log<'_>,
+    source: &Arc<str>,
+    ctx: &Context,
+    caches: &mut Caches,
+) -> Result<Line> {
+    let partition_by = &ctx.config.output.partition_by;
+    let partition = if partition_by.is_empty() {
+        String::new()
+    } else {
+        partition_of::<T>(log, partition_by)
+    };
+    let record = if !ctx.needs_record() {
+        Serialized::Text(render_log::<T>(log, &ctx.config.output)?)
+    } else {
+        let mut record = Record::from_log::<T>(log, ctx.config.decoding())?;
+        ctx.enricher.enrich(&mut record, caches);
+        match ctx.config.output.format {
+            Format::Sqlite => Serialized::Row(row_of(T::FIELDS, &record)),
+            _ => Serialized::Text(render_record::<T>(
+                log,
+                &record,
+                ctx.config.decoding(),
+                &ctx.config.output,
+            )?),
+        }
+    };
+    Ok(Line {
+        partition,
+        source: source.clone(),
+        record,
+    })
+}
+
+/// Parses every log file of `entries`, e.g. a `WalkDir` of the log directory, and hands
+/// serialized records over to `output` on the output thread.
+fn walkdir<T: LBLogParser>(
+    entries: impl IntoIterator<Item = walkdir::Result<DirEntry>>,
+    ctx: &Context,
+    output: impl FnOnce(Receiver<Line>) -> Result<()> + Send,
+) -> Result<()> {
     //
     // 1 walkdir thread  --------> N parsing/serializing worker threads --------> 1 output thread
     //   (main thread)     (t,r)            `worker_threads`             (tx,rx)   `output_thread`
     //
     let (t, r) = unbounded::<DirEntry>();
-    let (tx, rx) = unbounded::<String>();
-
-    // Create parsing/serializing worker threads
-    let worker_threads: Vec<_> = (0..thread::available_parallelism()?.get())
-        .map(|_| {
-            let r = r.clone();
-            let tx = tx.clone();
-            thread::spawn(move || -> Result<()> {
-                while let Ok(entry) = r.recv() {
-                    let path = entry.path();
-
-                    // ALB logs must ends with '.log.gz', and Classic LB logs must ends with '.log'
-                    if !path.to_str().map(|s| s.ends_with(T::EXT)).unwrap_or(false) {
-                        continue;
+    let (tx, rx) = unbounded::<Line>();
+    // Set once any thread failed, so that the others stop early.
+    let abort = &AtomicBool::new(false);
+
+    thread::scope(|scope| -> Result<()> {
+        // Create parsing/serializing worker threads
+        let worker_threads: Vec<_> = (0..thread::available_parallelism()?.get())
+            .map(|_| {
+                let r = r.clone();
+                let tx = tx.clone();
+                scope.spawn(move || -> Result<()> {
+                    let mut caches = Caches::default();
+                    let mut aggregate = Aggregate::default();
+                    while let Ok(entry) = r.recv() {
+                        if abort.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let result = parse_file::<T>(entry, ctx, &mut caches, &mut aggregate, &tx);
+                        if let Err(err) = result {
+                            abort.store(true, Ordering::Relaxed);
+                            return Err(err);
+                        }
                     }
-
-                    // Check for an empty file
-                    let metadata = metadata(path)?;
-                    if !metadata.is_file() || metadata.len() == 0 {
-                        continue;
+                    if let Some(metrics) = &ctx.metrics {
+                        metrics.merge(aggregate);
                     }
-
-                    let file = File::open(path)?;
-                    let reader: Box<dyn BufRead> = match T::TYPE {
-                        Type::Alb => Box::new(BufReader::new(MultiGzDecoder::new(file))),
-                        Type::ClassicLb => Box::new(BufReader::new(file)),
-                    };
-                    for_each_parsed_lines::<T>(reader, config, |log| {
-                        let json = serde_json::to_string(&log)?;
-                        tx.send(json)?;
-                        Ok(())
-                    })?;
-                }
-                Ok(())
+                    Ok(())
+                })
             })
-        })
-        .collect();
-    drop(r);
-    drop(tx);
-
-    // Create an output thread
-    let output_thread = thread::spawn(move || -> Result<()> {
-        let mut stdout = stdout().lock();
+            .collect();
+        drop(r);
+        drop(tx);
+
+        // Create an output thread
+        let output_thread = scope.spawn(move || -> Result<()> {
+            let result = output(rx);
+            if result.is_err() {
+                abort.store(true, Ordering::Relaxed);
+            }
+            result
+        });
+
+        // TODO: Apply parallelism
+        let mut results = Vec::new();
+        for entry in entries {
+            if abort.load(Ordering::Relaxed) {
+                break;
+            }
+            match entry {
+                Ok(entry) => t.send(entry)?,
+                Err(err) => {
+                    abort.store(true, Ordering::Relaxed);
+                    results.push(Err(err.into()));
+                    break;
+                }
+            }
+        }
+        drop(t);
 
-        while let Ok(json) = rx.recv() {
-            writeln!(stdout, "{json}")?;
+        for thread in worker_threads.into_iter().chain([output_thread]) {
+            match thread.join() {
+                Ok(result) => results.push(result),
+                Err(panic) => bail!("Thread panicked with error: {:?}", panic),
+            }
         }
-        drop(rx);
-        Ok(())
-    });
+        first_error(results)
+    })
+}
+
+fn parse_file<T: LBLogParser>(
+    entry: DirEntry,
+    ctx: &Context,
+    caches: &mut Caches,
+    aggregate: &mut Aggregate,
+    tx: &Sender<Line>,
+) -> Result<()> {
+    let path = entry.path();
 
-    // TODO: Apply parallelism
-    for entry in WalkDir::new(path) {
-        t.send(entry?)?;
+    // ALB logs must ends with '.log.gz', and Classic LB logs must ends with '.log'
+    if !path.to_str().map(|s| s.ends_with(T::EXT)).unwrap_or(false) {
+        return Ok(());
     }
-    drop(t);
 
-    // TODO: handle result
-    if let Err(panic) = output_thread.join() {
-        bail!("Thread panicked with error: {:?}", panic);
+    let metadata = metadata(path)?;
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    let source: Arc<str> = path.to_string_lossy().into();
+    let sqlite = ctx.config.output.format == Format::Sqlite && ctx.metrics.is_none();
+    let marker = |record| Line {
+        partition: String::new(),
+        source: source.clone(),
+        record,
+    };
+    if sqlite {
+        tx.send(marker(Serialized::FileStart))?;
     }
-    for thread in worker_threads {
-        if let Err(panic) = thread.join() {
-            bail!("Thread panicked with error: {:?}", panic);
+
+    // Check for an empty file
+    if metadata.len() == 0 {
+        if sqlite {
+            tx.send(marker(Serialized::FileEnd))?;
         }
+        return Ok(());
     }
 
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = match T::TYPE {
+        Type::Alb => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        Type::ClassicLb => Box::new(BufReader::new(file)),
+    };
+    for_each_parsed_lines::<T>(reader, &source, ctx, |log| {
+        match &ctx.metrics {
+            Some(metrics) => aggregate.add::<T>(log, metrics.step),
+            None => tx.send(serialize::<T>(log, &source, ctx, caches)?)?,
+        }
+        Ok(())
+    })
+    .map_err(|err| err.context(format!("Failed to process {source}")))?;
+    if sqlite {
+        tx.send(marker(Serialized::FileEnd))?;
+    }
     Ok(())
 }
 
+/// Picks the error to report among the results of every thread. Once a thread fails, the others
+/// often fail only because a channel got closed, so such errors are reported only if there is no
+/// other error.
+fn first_error(results: Vec<Result<()>>) -> Result<()> {
+    let mut errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+    let is_secondary = |err: &Error| err.chain().any(|cause| cause.is::<SendError<Line>>());
+    if let Some(idx) = errors.iter().position(|err| !is_secondary(err)) {
+        return Err(errors.swap_remove(idx));
+    }
+    errors.into_iter().next().map_or(Ok(()), Err)
+}
+
 fn for_each_parsed_lines<T: LBLogParser>(
     mut reader: impl BufRead,
-    config: Config,
+    source: &str,
+    ctx: &Context,
     mut callback: impl FnMut(&T::Log<'_>) -> Result<()>,
 ) -> Result<()> {
     let parser = T::new();
+    let first_read = ctx.budget.first_read(source);
     let mut buffer = Vec::new();
+    let mut line_number = 0;
     while reader.read_until(b'\n', &mut buffer)? > 0 {
-        let result = parser.parse(&buffer);
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            bail!("Interrupted");
+        }
+        line_number += 1;
+        let result = parser
+            .parse(&buffer)
+            .and_then(|log| match ctx.config.decoding.invalid_utf8 {
+                InvalidUtf8::Error => check_utf8::<T>(&buffer, log),
+                _ => Ok(log),
+            })
+            // Lines which failed to parse cannot be anonymized, so only where they failed is kept
+            .map_err(|err| match ctx.enricher.anonymizes() {
+                true => err.redact(),
+                false => err,
+            });
         let log = match &result {
             Ok(log) => log,
 
             //
             // Error handling
             //
-            Err(err) => {
-                reporter::<T>(config, err);
+            // Counted and reported already when the file was read first, e.g. by another scan of
+            // the same query
+            Err(_) if !first_read && ctx.budget.tolerates_errors() => {
+                drop(result);
+                buffer.clear();
+                continue;
+            }
 
-                if !config.skip_parse_errors {
-                    return Err(err.clone().into());
+            Err(err) => {
+                let skipped = ctx.budget.add_error(err);
+                reporter(skipped.is_ok(), err);
+                if let Some(rejects) = &ctx.rejects {
+                    rejects.write(source, line_number, &buffer)?;
                 }
+
+                skipped?;
                 drop(result);
                 buffer.clear();
                 continue;
@@ -208,29 +638,33 @@ fn for_each_parsed_lines<T: LBLogParser>(
         drop(result);
         buffer.clear();
     }
+    if first_read {
+        ctx.budget.add_lines(line_number);
+    }
     Ok(())
 }
 
-fn reporter<T: LBLogParser>(config: Config, err: &ParseLogError) {
-    if !stderr().is_terminal() {
-        if config.skip_parse_errors {
+fn reporter(skipped: bool, err: &ParseLogError) {
+    if !stderr().is_terminal() || err.line().is_empty() {
+        if skipped {
             eprintln!("Skipping error: {}", err);
         } else {
             eprintln!("Error: {}", err);
         }
     } else {
-        let msg = if config.skip_parse_errors {
+        let msg = if skipped {
             "\x1b[33mFailed to parse following line, skipping:\x1b[0m"
         } else {
             "\x1b[31mThread panicked due to parsing failure:\x1b[0m"
         };
 
-        let ParseLogError::InvalidLogFormat(log) = err;
+        let log = err.line();
+        let diagnostic = err.diagnostic();
 
-        match T::try_find_failed_position(log) {
-            None => eprintln!("{}\n    {}\n", msg, String::from_utf8_lossy(log).trim_end()),
+        match diagnostic.position {
+            None => eprintln!("{}\n    {}", msg, String::from_utf8_lossy(log).trim_end()),
             Some(idx) if idx < log.len() => eprintln!(
-                "{}\n    {}\x1b[1;91;4;31m{}\x1b[0m\x1b[38;5;238m{}\x1b[0m\n",
+                "{}\n    {}\x1b[1;91;4;31m{}\x1b[0m\x1b[38;5;238m{}\x1b[0m",
                 msg,
                 // TODO: Properly detect the border of grapheme clusters around 'idx'
                 String::from_utf8_lossy(&log[..idx]),
@@ -238,10 +672,116 @@ fn reporter<T: LBLogParser>(config: Config, err: &ParseLogError) {
                 String::from_utf8_lossy(&log[idx + 1..]).trim_end(),
             ),
             Some(_) => eprintln!(
-                "{}\n    {} \x1b[91m(expected next input, but received none)\x1b[0m\n",
+                "{}\n    {} \x1b[91m(expected next input, but received none)\x1b[0m",
                 msg,
                 String::from_utf8_lossy(log).trim_end()
             ),
         }
+        eprintln!("    \x1b[91m{diagnostic}\x1b[0m\n");
     }
 }
+
+#[test]
+fn test_invalid_utf8_lines() {
+    let dir = std::env::temp_dir().join(format!("elb-log-parser-utf8-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let rejects = dir.join("rejects.tsv");
+    let args = Args::try_parse_from([
+        "elb-log-parser",
+        "--skip-parse-errors",
+        "--rejects",
+        rejects.to_str().unwrap(),
+        "-",
+    ])
+    .unwrap();
+    let ctx = Context::new(args.config, None).unwrap();
+
+    let valid = br#"http 2022-11-03T21:10:11.091427Z app/my-alb/1234567890abcdef 123.123.123.123:65432 - -1 -1 -1 400 - 0 272 "- http://example.com:8080- -" "-" - - - "-" "-" "-" - 2022-11-03T21:10:10.933000Z "-" "-" "-" "-" "-" "-" "-""#;
+    // User agent of `valid` replaced with an incomplete UTF-8 sequence
+    let invalid = b"http 2022-11-03T21:10:11.091427Z app/my-alb/1234567890abcdef 123.123.123.123:65432 - -1 -1 -1 400 - 0 272 \"- http://example.com:8080- -\" \"\xEC\x97\" - - - \"-\" \"-\" \"-\" - 2022-11-03T21:10:10.933000Z \"-\" \"-\" \"-\" \"-\" \"-\" \"-\" \"-\"";
+    let input = [&valid[..], b"\n", invalid, b"\n", valid, b"\n"].concat();
+
+    // By default, a line with invalid UTF-8 is skipped like a parse error rather than failing
+    // the output
+    let source: Arc<str> = "mixed.log".into();
+    let mut caches = Caches::default();
+    let mut records = Vec::new();
+    for_each_parsed_lines::<ALBLogParser>(&input[..], &source, &ctx, |log| {
+        let Serialized::Text(record) =
+            serialize::<ALBLogParser>(log, &source, &ctx, &mut caches)?.record
+        else {
+            panic!("Expected a JSON record");
+        };
+        records.push(record);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(ctx.budget.errors(), 1);
+
+    ctx.rejects.unwrap().finish().unwrap();
+    let mut expected = b"2\tmixed.log\t".to_vec();
+    expected.extend_from_slice(invalid);
+    expected.push(b'\n');
+    assert_eq!(std::fs::read(&rejects).unwrap(), expected);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_anonymized_outputs() {
+    let args = |extra: &[&str]| {
+        let base = ["elb-log-parser", "--anonymize", "--format", "es-bulk"];
+        Args::try_parse_from(base.iter().chain(extra).chain(&["--index", "alb", "-"]))
+    };
+    // Rejected lines would be written as is
+    let rejects = args(&["--rejects", "rejects.tsv"]).unwrap();
+    assert!(Context::new(rejects.config, None).is_err());
+
+    let valid = br#"http 2022-11-03T21:10:11.091427Z app/my-alb/1234567890abcdef 123.123.123.123:65432 - -1 -1 -1 400 - 0 272 "- http://example.com:8080- -" "-" - - - "-" "-" "-" - 2022-11-03T21:10:10.933000Z "-" "-" "-" "-" "-" "-" "-""#;
+    let invalid = br#"http 2022-11-03T21:10:11.091427Z app/my-alb/1234567890abcdef 123.123.123.123:65432 - -1 -1 -1 400 - 0 272 "- http://example.com:8080- -" "-" - TLS1.3 - "-" "-" "-" - 2022-11-03T21:10:10.933000Z "-" "-" "-" "-" "-" "-" "-""#;
+    let source: Arc<str> = "-".into();
+    let run = |ctx: &Context, input: &[u8]| {
+        let mut caches = Caches::default();
+        let mut records = Vec::new();
+        let result = for_each_parsed_lines::<ALBLogParser>(input, &source, ctx, |log| {
+            let Serialized::Text(record) =
+                serialize::<ALBLogParser>(log, &source, ctx, &mut caches)?.record
+            else {
+                panic!("Expected a JSON record");
+            };
+            records.push(record);
+            Ok(())
+        });
+        (result, records)
+    };
+
+    // Neither the record nor `_id` of es-bulk is derived from the client IP
+    let ctx = Context::new(args(&[]).unwrap().config, None).unwrap();
+    let (result, records) = run(&ctx, valid);
+    result.unwrap();
+    assert!(records[0].contains("123.123.123.0"));
+    assert!(!records[0].contains("123.123.123.123"));
+    let plain = Context::new(
+        Args::try_parse_from([
+            "elb-log-parser",
+            "--format",
+            "es-bulk",
+            "--index",
+            "alb",
+            "-",
+        ])
+        .unwrap()
+        .config,
+        None,
+    )
+    .unwrap();
+    let (_, plain_records) = run(&plain, valid);
+    let id = |record: &str| record.lines().next().unwrap().to_owned();
+    assert_ne!(id(&records[0]), id(&plain_records[0]));
+
+    // Nor is the error of a line which failed to parse, which is what gets reported on stderr
+    let (result, _) = run(&ctx, invalid);
+    let err = format!("{:?}", result.unwrap_err());
+    assert!(err.contains("field `ssl_protocol` (19)"), "{err}");
+    assert!(!err.contains("123.123.123.123"), "{err}");
+}
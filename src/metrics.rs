@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use jiff::Timestamp;
+
+use crate::format::processing_time;
+use crate::parse::{LBLogParser, LogFields};
+
+/// Upper bounds of the buckets of the latency histogram, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Values of `elb`, status class, `target_group_arn` and `domain_name`
+type Labels = [String; 4];
+
+const LABEL_NAMES: [&str; 4] = ["elb", "status_class", "target_group_arn", "domain_name"];
+
+/// Requests and their latencies during a single step
+#[derive(Default, Clone)]
+struct Point {
+    requests: u64,
+    /// Number of requests whose latency is at most each bucket. Requests which did not reach a
+    /// target are not in the histogram, since their latency is unknown.
+    buckets: [u64; BUCKETS.len()],
+    latencies: u64,
+    latency_sum: f64,
+}
+
+impl Point {
+    fn add(&mut self, other: &Point) {
+        self.requests += other.requests;
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+        self.latencies += other.latencies;
+        self.latency_sum += other.latency_sum;
+    }
+}
+
+/// Aggregated points of each series, keyed by the start of the step in Unix seconds, which is
+/// accumulated by each thread and merged into `Metrics` at the end.
+#[derive(Default)]
+pub(crate) struct Aggregate {
+    points: HashMap<(Labels, i64), Point>,
+}
+
+impl Aggregate {
+    pub(crate) fn add<T: LBLogParser>(&mut self, log: &T::Log<'_>, step: i64) {
+        let fields = log.fields();
+        let field = |name: &str| {
+            let idx = T::FIELDS.iter().position(|f| *f == name)?;
+            Some(String::from_utf8_lossy(fields[idx]?).into_owned())
+        };
+        let Some(time) = field("time").and_then(|time| time.parse::<Timestamp>().ok()) else {
+            return;
+        };
+        let status_class = match field("elb_status_code") {
+            Some(code) if code.len() == 3 && code.bytes().all(|b| b.is_ascii_digit()) => {
+                format!("{}xx", &code[..1])
+            }
+            _ => "none".to_owned(),
+        };
+        // "-" means that there is no such value
+        let label = |name: &str| field(name).filter(|value| value != "-").unwrap_or_default();
+        let labels = [
+            label("elb"),
+            status_class,
+            label("target_group_arn"),
+            label("domain_name"),
+        ];
+        let start = time.as_second().div_euclid(step) * step;
+
+        let point = self.points.entry((labels, start)).or_default();
+        point.requests += 1;
+        if let Some(latency) = processing_time(field) {
+            for (bucket, le) in point.buckets.iter_mut().zip(BUCKETS) {
+                if latency <= le {
+                    *bucket += 1;
+                }
+            }
+            point.latencies += 1;
+            point.latency_sum += latency;
+        }
+    }
+}
+
+/// Request counters and latency histograms over time, which are written as OpenMetrics text with
+/// a sample at the end of every step. Every sample is cumulative since the first log, so that the
+/// output can be imported with `promtool tsdb create-blocks-from openmetrics`.
+pub(crate) struct Metrics {
+    /// Length of each step in seconds
+    pub(crate) step: i64,
+    aggregate: Mutex<Aggregate>,
+}
+
+impl Metrics {
+    pub(crate) fn new(step: i64) -> Self {
+        Self {
+            step,
+            aggregate: Mutex::new(Aggregate::default()),
+        }
+    }
+
+    pub(crate) fn merge(&self, other: Aggregate) {
+        let mut aggregate = self.aggregate.lock().unwrap();
+        for (key, point) in other.points {
+            aggregate.points.entry(key).or_default().add(&point);
+        }
+    }
+
+    pub(crate) fn write(self, out: &mut impl Write) -> Result<()> {
+        // Series in a stable order, each of which has its points in order of time
+        let mut series: BTreeMap<Labels, BTreeMap<i64, Point>> = BTreeMap::new();
+        for ((labels, start), point) in self.aggregate.into_inner().unwrap().points {
+            series.entry(labels).or_default().insert(start, point);
+        }
+        let cumulative = |points: &BTreeMap<i64, Point>| {
+            let mut total = Point::default();
+            points
+                .iter()
+                .map(|(start, point)| {
+                    total.add(point);
+                    (start + self.step, total.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        writeln!(out, "# TYPE elb_requests counter")?;
+        writeln!(
+            out,
+            "# HELP elb_requests Requests handled by the load balancer."
+        )?;
+        for (labels, points) in &series {
+            let labels = format_labels(labels, None);
+            for (time, total) in cumulative(points) {
+                writeln!(out, "elb_requests_total{labels} {} {time}", total.requests)?;
+            }
+        }
+
+        let name = "elb_request_duration_seconds";
+        writeln!(out, "# TYPE {name} histogram")?;
+        writeln!(out, "# UNIT {name} seconds")?;
+        writeln!(
+            out,
+            "# HELP {name} Total processing time of requests which reached a target."
+        )?;
+        for (labels, points) in &series {
+            for (time, total) in cumulative(points) {
+                for (count, le) in total.buckets.iter().zip(BUCKETS) {
+                    let labels = format_labels(labels, Some(&format!("{le:?}")));
+                    writeln!(out, "{name}_bucket{labels} {count} {time}")?;
+                }
+                let inf = format_labels(labels, Some("+Inf"));
+                writeln!(out, "{name}_bucket{inf} {} {time}", total.latencies)?;
+                let labels = format_labels(labels, None);
+                writeln!(out, "{name}_count{labels} {} {time}", total.latencies)?;
+                writeln!(out, "{name}_sum{labels} {} {time}", total.latency_sum)?;
+            }
+        }
+        writeln!(out, "# EOF")?;
+        Ok(())
+    }
+}
+
+/// Formats labels, e.g. `{elb="app/my-alb/123",status_class="2xx",...}`
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let escape = |value: &str| {
+        value
+            .replace('\\', r"\\")
+            .replace('"', r#"\""#)
+            .replace('\n', r"\n")
+    };
+    let mut pairs: Vec<_> = LABEL_NAMES
+        .iter()
+        .zip(labels)
+        .map(|(name, value)| format!(r#"{name}="{}""#, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!(r#"le="{le}""#));
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Parses a step such as "30s", "1m" or "1h" into seconds.
+pub(crate) fn parse_step(s: &str) -> Result<i64, String> {
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("{s} has an unknown unit, which must be s, m or h")),
+    };
+    match number.parse::<i64>() {
+        Ok(number) if number > 0 => Ok(number * seconds),
+        _ => Err(format!("{s} is not a positive duration, e.g. 1m")),
+    }
+}
+
+#[test]
+fn test_metrics() {
+    use crate::alb::LogParser;
+
+    let parser = LogParser::new();
+    let line = |time: &str, status: &str, target_processing_time: &str| {
+        format!(
+            r#"h2 {time} app/myalb/0123456789abcdef 1.123.123.123:12345 10.0.1.100:80 0.000 {target_processing_time} 0.000 {status} {status} 315 488 "GET https://example.com:443/ HTTP/2.0" "curl/8.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 arn:aws:elasticloadbalancing:ap-northeast-2:012345678901:targetgroup/tg/0123456789abcdef "Root=1-abcd0123-0123456789abcdef01234567" "example.com" "-" 1 {time} "forward" "-" "-" "10.0.1.100:80" "{status}" "-" "-""#
+        )
+    };
+    let mut aggregate = Aggregate::default();
+    for (time, status, target_processing_time) in [
+        ("2020-01-11T01:11:10.111111Z", "200", "0.040"),
+        ("2020-01-11T01:11:50.111111Z", "200", "0.300"),
+        ("2020-01-11T01:12:10.111111Z", "502", "-1"),
+        ("2020-01-11T01:13:10.111111Z", "200", "0.010"),
+    ] {
+        let line = line(time, status, target_processing_time);
+        aggregate.add::<LogParser>(&parser.parse(line.as_bytes()).unwrap(), 60);
+    }
+    let metrics = Metrics::new(60);
+    metrics.merge(aggregate);
+    let mut out = Vec::new();
+    metrics.write(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    let labels = r#"elb="app/myalb/0123456789abcdef",status_class="2xx",target_group_arn="arn:aws:elasticloadbalancing:ap-northeast-2:012345678901:targetgroup/tg/0123456789abcdef",domain_name="example.com""#;
+    let labels_5xx = labels.replace("2xx", "5xx");
+    for expected in [
+        // Counters are cumulative, and sampled at the end of each step
+        format!("elb_requests_total{{{labels}}} 2 1578705120\n"),
+        format!("elb_requests_total{{{labels}}} 3 1578705240\n"),
+        format!(r#"elb_request_duration_seconds_bucket{{{labels},le="0.05"}} 1 1578705120"#),
+        format!(r#"elb_request_duration_seconds_bucket{{{labels},le="0.05"}} 2 1578705240"#),
+        format!("elb_request_duration_seconds_count{{{labels}}} 3 1578705240\n"),
+        // Requests which did not reach a target are counted, but not in the histogram
+        format!("elb_requests_total{{{labels_5xx}}} 1 1578705180\n"),
+        format!("elb_request_duration_seconds_count{{{labels_5xx}}} 0 1578705180\n"),
+    ] {
+        assert!(out.contains(&expected), "{expected} is not in {out}");
+    }
+    assert!(out.ends_with("# EOF\n"));
+
+    assert_eq!(parse_step("5m"), Ok(300));
+    assert!(parse_step("0s").is_err());
+    assert!(parse_step("1d").is_err());
+}
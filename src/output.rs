@@ -0,0 +1,254 @@
+mod compress;
+mod partition;
+mod sqlite;
+
+use std::fs::{File, create_dir_all, read_dir};
+use std::io::{BufWriter, ErrorKind, Write, stdout};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use rusqlite::types::Value as SqlValue;
+
+pub(crate) use self::compress::Compress;
+use self::compress::ParallelEncoder;
+use self::partition::PartitionedFiles;
+pub(crate) use self::partition::{PartitionKey, partition_of};
+use self::sqlite::SqliteTable;
+pub(crate) use self::sqlite::{column_definitions, row_of};
+use crate::format::{Format, FormatConfig};
+use crate::parse::LBLogParser;
+
+/// Serialized record, along with the partition which it belongs to and the file which it came
+/// from. The partition is empty unless --partition-by is given.
+pub(crate) struct Line {
+    pub(crate) partition: String,
+    pub(crate) source: Arc<str>,
+    pub(crate) record: Serialized,
+}
+
+pub(crate) enum Serialized {
+    /// Record in a text format, which may span multiple lines
+    Text(String),
+    /// Values of the columns of a record, for --format sqlite
+    Row(Vec<SqlValue>),
+    /// Start of a file, which is sent before its rows for --format sqlite, so that rows
+    /// previously loaded from the file are replaced even if it has no rows now
+    FileStart,
+    /// End of a file, which is sent once every row of the file is sent for --format sqlite
+    FileEnd,
+}
+
+/// Destination of serialized records, which is written only by a single thread.
+pub(crate) enum Output {
+    Stream(Box<dyn Write>),
+    Compressed(ParallelEncoder<Box<dyn Write>>),
+    Split(SplitFiles),
+    Partitioned(PartitionedFiles),
+    Sqlite(SqliteTable),
+}
+
+impl Output {
+    pub(crate) fn create<T: LBLogParser>(config: &FormatConfig) -> Result<Self> {
+        if config.bulk_dir.is_some() && config.format != Format::EsBulk {
+            bail!("--bulk-dir requires --format es-bulk");
+        }
+        if config.format == Format::Sqlite {
+            if config.compress.is_some() {
+                bail!("--format sqlite cannot be compressed");
+            }
+            let path = config.output_file.as_deref();
+            let path = path.expect("--output is required by --format sqlite");
+            return Ok(Output::Sqlite(SqliteTable::open::<T>(path)?));
+        }
+        Ok(match (&config.output_dir, &config.bulk_dir) {
+            (Some(dir), _) => Output::Partitioned(PartitionedFiles::new(
+                dir.clone(),
+                config.format.extension(),
+                config.part_size,
+            )),
+            (None, Some(dir)) => {
+                Output::Split(SplitFiles::create(dir, "bulk", "ndjson", config.bulk_size)?)
+            }
+            (None, None) => {
+                let compress = config.compress.unwrap_or_else(|| {
+                    config
+                        .output_file
+                        .as_deref()
+                        .map_or(Compress::None, Compress::of_path)
+                });
+                let level = compress.level(config.compress_level)?;
+                let stream: Box<dyn Write> = match &config.output_file {
+                    Some(path) => {
+                        Box::new(BufWriter::new(File::create(path).with_context(|| {
+                            format!("Failed to create {}", path.display())
+                        })?))
+                    }
+                    None => Box::new(stdout().lock()),
+                };
+                match compress {
+                    Compress::None => Output::Stream(stream),
+                    _ => Output::Compressed(ParallelEncoder::new(stream, compress, level)?),
+                }
+            }
+        })
+    }
+
+    /// Writes a record followed by a newline.
+    pub(crate) fn write(&mut self, line: &Line) -> Result<()> {
+        match (self, &line.record) {
+            (Output::Sqlite(table), _) => table.write(line)?,
+            (Output::Stream(stream), Serialized::Text(text)) => writeln!(stream, "{text}")?,
+            (Output::Compressed(encoder), Serialized::Text(text)) => writeln!(encoder, "{text}")?,
+            (Output::Split(files), Serialized::Text(text)) => files.write(text)?,
+            (Output::Partitioned(files), Serialized::Text(text)) => {
+                files.write(&line.partition, text)?
+            }
+            (_, Serialized::Row(_) | Serialized::FileStart | Serialized::FileEnd) => {
+                unreachable!("Rows are serialized only for --format sqlite")
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        match self {
+            Output::Stream(mut stream) => stream.flush()?,
+            Output::Compressed(encoder) => drop(encoder.finish()?),
+            Output::Split(files) => files.finish()?,
+            Output::Partitioned(files) => files.finish()?,
+            Output::Sqlite(table) => table.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// Numbered files in a directory, e.g. `bulk-00000.ndjson`, each of which holds at most
+/// `max_bytes` unless a single record is larger than that. A record is never split across files.
+pub(crate) struct SplitFiles {
+    dir: PathBuf,
+    prefix: &'static str,
+    ext: &'static str,
+    max_bytes: u64,
+    count: usize,
+    current: Option<(BufWriter<File>, u64)>,
+}
+
+impl SplitFiles {
+    /// Numbering continues after the files left by earlier runs, which are never overwritten.
+    fn create(dir: &Path, prefix: &'static str, ext: &'static str, max_bytes: u64) -> Result<Self> {
+        create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            prefix,
+            ext,
+            max_bytes,
+            count: next_number(dir, prefix)?,
+            current: None,
+        })
+    }
+
+    fn write(&mut self, record: &str) -> Result<()> {
+        let len = record.len() as u64 + 1;
+        if let Some((_, written)) = &self.current
+            && *written + len > self.max_bytes
+        {
+            let (mut file, _) = self.current.take().unwrap();
+            file.flush()?;
+        }
+        let (file, written) = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let path = self
+                    .dir
+                    .join(format!("{}-{:05}.{}", self.prefix, self.count, self.ext));
+                self.count += 1;
+                let file = File::create(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                self.current.insert((BufWriter::new(file), 0))
+            }
+        };
+        writeln!(file, "{record}")?;
+        *written += len;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Some((mut file, _)) = self.current {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Number which follows every `<prefix>-NNNNN.*` in `dir`, so that files written by earlier runs
+/// are kept
+fn next_number(dir: &Path, prefix: &str) -> Result<usize> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut next = 0;
+    for entry in entries {
+        let name = entry?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix)?.strip_prefix('-'))
+            .and_then(|name| name.split('.').next())
+            .and_then(|number| number.parse::<usize>().ok());
+        if let Some(number) = number {
+            next = next.max(number + 1);
+        }
+    }
+    Ok(next)
+}
+
+/// Parses a size in bytes, e.g. "1048576", "512K", "10MiB" or "1G". Units are powers of 1024.
+pub(crate) fn parse_size(s: &str) -> Result<u64, String> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("{s} is not a size, e.g. 10MiB"))?;
+    let shift = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("{s} has an unknown unit, which must be K, M or G")),
+    };
+    match number.checked_mul(1 << shift) {
+        Some(0) | None => Err(format!("{s} must be positive and less than 2^64 bytes")),
+        Some(bytes) => Ok(bytes),
+    }
+}
+
+#[test]
+fn test_split_files() {
+    let dir = std::env::temp_dir().join(format!("elb-log-parser-test-{}", std::process::id()));
+    let mut files = SplitFiles::create(&dir, "bulk", "ndjson", 10).unwrap();
+    for record in ["1234", "5678", "90", "a very long record", "b"] {
+        files.write(record).unwrap();
+    }
+    files.finish().unwrap();
+    let read = |n: usize| std::fs::read_to_string(dir.join(format!("bulk-{n:05}.ndjson"))).unwrap();
+    assert_eq!(read(0), "1234\n5678\n");
+    assert_eq!(read(1), "90\n");
+    assert_eq!(read(2), "a very long record\n");
+    assert_eq!(read(3), "b\n");
+
+    // Another run continues after the existing files
+    let mut files = SplitFiles::create(&dir, "bulk", "ndjson", 10).unwrap();
+    files.write("c").unwrap();
+    files.finish().unwrap();
+    assert_eq!(read(0), "1234\n5678\n");
+    assert_eq!(read(4), "c\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(parse_size("10MiB"), Ok(10 << 20));
+    assert_eq!(parse_size("512K"), Ok(512 << 10));
+    assert_eq!(parse_size("100"), Ok(100));
+    assert!(parse_size("10 parsecs").is_err());
+    assert!(parse_size("0").is_err());
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use crossbeam_channel::{Receiver, Sender, bounded};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/// Size of each block which is compressed independently of the others
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// Compression algorithm of --output
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Compress {
+    /// Write as is
+    None,
+    /// gzip, with levels from 0 to 9
+    Gzip,
+    /// Zstandard, with levels from 1 to 22
+    Zstd,
+}
+
+impl Compress {
+    /// Compression implied by the extension of the output file, e.g. "out.jsonl.zst"
+    pub(crate) fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("gz") => Compress::Gzip,
+            Some("zst") => Compress::Zstd,
+            _ => Compress::None,
+        }
+    }
+
+    /// Validates the given compression level, or returns the default one.
+    pub(super) fn level(self, level: Option<u32>) -> Result<u32> {
+        let (default, range) = match self {
+            Compress::None if level.is_some() => {
+                bail!("--compress-level requires --compress, or --output ending with .gz or .zst")
+            }
+            Compress::None => (0, 0..=0),
+            Compress::Gzip => (6, 0..=9),
+            Compress::Zstd => (3, 1..=22),
+        };
+        match level {
+            None => Ok(default),
+            Some(level) if range.contains(&level) => Ok(level),
+            Some(level) => bail!(
+                "--compress-level {level} is out of range {}..={} of {self:?}",
+                range.start(),
+                range.end()
+            ),
+        }
+    }
+
+    fn compress(self, block: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        match self {
+            Compress::None => Ok(block.to_vec()),
+            Compress::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(block)?;
+                encoder.finish()
+            }
+            Compress::Zstd => zstd::encode_all(block, level as i32),
+        }
+    }
+}
+
+type Job = (Vec<u8>, Sender<io::Result<Vec<u8>>>);
+
+/// Writer which splits its input into blocks and compresses them on a pool of threads, so that
+/// compression does not bottleneck the output thread. Each block becomes an independent gzip
+/// member or zstd frame, whose concatenation is decompressed by `zcat` and `zstdcat` as a whole.
+pub(crate) struct ParallelEncoder<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    jobs: Option<Sender<Job>>,
+    pending: VecDeque<Receiver<io::Result<Vec<u8>>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<W: Write> ParallelEncoder<W> {
+    pub(crate) fn new(inner: W, compress: Compress, level: u32) -> Result<Self> {
+        let count = thread::available_parallelism()?.get();
+        let (jobs, rx) = bounded::<Job>(count);
+        let threads = (0..count)
+            .map(|_| {
+                let rx = rx.clone();
+                thread::spawn(move || {
+                    for (block, result) in rx {
+                        let _ = result.send(compress.compress(&block, level));
+                    }
+                })
+            })
+            .collect();
+        Ok(Self {
+            inner,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            jobs: Some(jobs),
+            pending: VecDeque::new(),
+            threads,
+        })
+    }
+
+    /// Hands the buffered block over to the pool, and writes finished blocks in order while too
+    /// many of them are in flight.
+    fn submit(&mut self) -> io::Result<()> {
+        let block = mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+        let (tx, rx) = bounded(1);
+        let jobs = self.jobs.as_ref().expect("encoder is already finished");
+        jobs.send((block, tx)).map_err(io::Error::other)?;
+        self.pending.push_back(rx);
+        while self.pending.len() > 2 * self.threads.len() {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        let Some(rx) = self.pending.pop_front() else {
+            return Ok(());
+        };
+        let compressed = rx.recv().map_err(io::Error::other)??;
+        self.inner.write_all(&compressed)
+    }
+
+    /// Compresses the rest of the input and waits for every block to be written.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.submit()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_pending()?;
+        }
+        drop(self.jobs.take());
+        for thread in self.threads.drain(..) {
+            thread
+                .join()
+                .map_err(|_| io::Error::other("compression thread panicked"))?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ParallelEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.submit()?;
+        }
+        Ok(len)
+    }
+
+    /// Blocks are compressed only once they are full or the encoder is finished, so this flushes
+    /// the underlying writer only.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_parallel_encoder() {
+    use std::io::Read;
+
+    let input: Vec<u8> = (0..3 * BLOCK_SIZE + 12345)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    for compress in [Compress::None, Compress::Gzip, Compress::Zstd] {
+        let mut encoder =
+            ParallelEncoder::new(Vec::new(), compress, compress.level(None).unwrap()).unwrap();
+        encoder.write_all(&input).unwrap();
+        let output = encoder.finish().unwrap();
+        let mut decoded = Vec::new();
+        match compress {
+            Compress::None => decoded = output,
+            Compress::Gzip => {
+                flate2::read::MultiGzDecoder::new(&output[..])
+                    .read_to_end(&mut decoded)
+                    .unwrap();
+            }
+            Compress::Zstd => decoded = zstd::decode_all(&output[..]).unwrap(),
+        }
+        assert!(decoded == input, "{compress:?}");
+    }
+
+    assert_eq!(
+        Compress::of_path(Path::new("out.jsonl.zst")),
+        Compress::Zstd
+    );
+    assert_eq!(Compress::of_path(Path::new("out.jsonl")), Compress::None);
+    assert!(Compress::Gzip.level(Some(10)).is_err());
+}
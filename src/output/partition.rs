@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fs::{File, create_dir_all};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use jiff::Timestamp;
+use jiff::tz::TimeZone;
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+
+use super::next_number;
+use crate::parse::{LBLogParser, LogFields};
+
+/// Partition values which are used when the value is missing, as Hive does
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Characters which are escaped in partition values, as Hive does
+const ESCAPED: &AsciiSet = &CONTROLS
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'\'')
+    .add(b'*')
+    .add(b'/')
+    .add(b':')
+    .add(b'=')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'[')
+    .add(b']')
+    .add(b'^')
+    .add(b'{');
+
+/// Writers which are open at once at most. Once there are more partitions than this, the least
+/// recently used writer is finished, and later records of its partition go into a new part.
+const MAX_OPEN_WRITERS: usize = 128;
+
+/// Key of the Hive-style directories which records are partitioned into
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum PartitionKey {
+    /// `elb=app%2Fmy-alb%2F50dc6c495c0c9188`
+    Elb,
+    /// `dt=2024-05-28`, in UTC
+    Date,
+    /// `hr=13`, in UTC
+    Hour,
+}
+
+/// Relative directory of the partition which a log belongs to, e.g.
+/// `elb=app%2Fmy-alb%2F50dc6c495c0c9188/dt=2024-05-28/hr=13`
+pub(crate) fn partition_of<T: LBLogParser>(log: &T::Log<'_>, keys: &[PartitionKey]) -> String {
+    let fields = log.fields();
+    let field = |name: &str| {
+        let idx = T::FIELDS.iter().position(|f| *f == name)?;
+        Some(String::from_utf8_lossy(fields[idx]?))
+    };
+    let time = field("time").and_then(|time| time.parse::<Timestamp>().ok());
+    let time = time.map(|time| time.to_zoned(TimeZone::UTC));
+
+    let dirs: Vec<_> = keys
+        .iter()
+        .map(|key| {
+            let (name, value) = match key {
+                PartitionKey::Elb => ("elb", field("elb").map(|elb| elb.into_owned())),
+                PartitionKey::Date => (
+                    "dt",
+                    time.as_ref().map(|t| t.strftime("%Y-%m-%d").to_string()),
+                ),
+                PartitionKey::Hour => ("hr", time.as_ref().map(|t| t.strftime("%H").to_string())),
+            };
+            let value = match value {
+                Some(value) if !value.is_empty() && value != "-" => {
+                    utf8_percent_encode(&value, ESCAPED).to_string()
+                }
+                _ => DEFAULT_PARTITION.to_owned(),
+            };
+            format!("{name}={value}")
+        })
+        .collect();
+    dirs.join("/")
+}
+
+/// Gzip compressed files of each partition, e.g. `out/dt=2024-05-28/hr=13/part-00000.jsonl.gz`,
+/// which roll over once they exceed `max_bytes` after compression. Parts are numbered after those
+/// already in the directory of each partition, so that earlier runs are never overwritten.
+///
+/// Writers are finished even if the run fails halfway, since `GzEncoder` finishes itself when
+/// dropped, so that every file is a valid gzip file.
+pub(crate) struct PartitionedFiles {
+    dir: PathBuf,
+    ext: &'static str,
+    max_bytes: u64,
+    partitions: HashMap<String, Partition>,
+    open: usize,
+    /// Incremented on every record, to find the least recently used writer
+    clock: u64,
+}
+
+struct Partition {
+    parts: usize,
+    writer: Option<GzEncoder<Counter<BufWriter<File>>>>,
+    last_used: u64,
+}
+
+impl PartitionedFiles {
+    pub(crate) fn new(dir: PathBuf, ext: &'static str, max_bytes: u64) -> Self {
+        Self {
+            dir,
+            ext,
+            max_bytes,
+            partitions: HashMap::new(),
+            open: 0,
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn write(&mut self, partition: &str, record: &str) -> Result<()> {
+        if !self.partitions.contains_key(partition) {
+            let current = Partition {
+                parts: next_number(&self.dir.join(partition), "part")?,
+                writer: None,
+                last_used: 0,
+            };
+            self.partitions.insert(partition.to_owned(), current);
+        }
+        let current = self.partitions.get_mut(partition).unwrap();
+        if let Some(writer) = &current.writer
+            && writer.get_ref().bytes >= self.max_bytes
+        {
+            current.writer.take().unwrap().finish()?.inner.flush()?;
+            self.open -= 1;
+        }
+        if current.writer.is_none() && self.open >= MAX_OPEN_WRITERS {
+            self.finish_least_recently_used()?;
+        }
+
+        self.clock += 1;
+        let current = self.partitions.get_mut(partition).unwrap();
+        current.last_used = self.clock;
+        let writer = match &mut current.writer {
+            Some(writer) => writer,
+            None => {
+                let dir = self.dir.join(partition);
+                create_dir_all(&dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+                let path = dir.join(format!("part-{:05}.{}.gz", current.parts, self.ext));
+                let file = File::create(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                current.parts += 1;
+                self.open += 1;
+                let counter = Counter {
+                    inner: BufWriter::new(file),
+                    bytes: 0,
+                };
+                current
+                    .writer
+                    .insert(GzEncoder::new(counter, Compression::default()))
+            }
+        };
+        writeln!(writer, "{record}")?;
+        Ok(())
+    }
+
+    fn finish_least_recently_used(&mut self) -> Result<()> {
+        let lru = self
+            .partitions
+            .values_mut()
+            .filter(|partition| partition.writer.is_some())
+            .min_by_key(|partition| partition.last_used);
+        if let Some(writer) = lru.and_then(|partition| partition.writer.take()) {
+            writer.finish()?.inner.flush()?;
+            self.open -= 1;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        for partition in self.partitions.into_values() {
+            if let Some(writer) = partition.writer {
+                writer.finish()?.inner.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts the bytes written into the inner writer, i.e. the compressed size
+struct Counter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_partition() {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use crate::alb::LogParser;
+
+    let parser = LogParser::new();
+    let line = br#"h2 2024-05-28T13:34:14.804475Z app/myalb/7bba4eaafdb3bbc6 18.180.78.42:42088 172.31.11.24:80 0.006 0.000 0.000 200 200 115 124 "GET http://alb-example.ap-northeast-1.elb.amazonaws.com:80/ HTTP/1.1" "curl/8.0" - - arn:aws:elasticloadbalancing:ap-northeast-1:012345678901:targetgroup/tg/0123456789abcdef "Root=1-abcd0123-0123456789abcdef01234567" "-" "-" 0 2024-05-28T13:34:14.797000Z "forward" "-" "-" "172.31.11.24:80" "200" "-" "-""#;
+    let log = parser.parse(line).unwrap();
+    assert_eq!(
+        partition_of::<LogParser>(
+            &log,
+            &[PartitionKey::Elb, PartitionKey::Date, PartitionKey::Hour]
+        ),
+        "elb=app%2Fmyalb%2F7bba4eaafdb3bbc6/dt=2024-05-28/hr=13"
+    );
+
+    let dir = std::env::temp_dir().join(format!("elb-log-parser-partition-{}", std::process::id()));
+    let mut files = PartitionedFiles::new(dir.clone(), "jsonl", 1);
+    files.write("dt=2024-05-28", "a").unwrap();
+    files.write("dt=2024-05-29", "b").unwrap();
+    // Parts roll over once they exceed the size, which is one byte here
+    files.write("dt=2024-05-28", "c").unwrap();
+    files.finish().unwrap();
+    let read = |path: &str| {
+        let mut s = String::new();
+        GzDecoder::new(File::open(dir.join(path)).unwrap())
+            .read_to_string(&mut s)
+            .unwrap();
+        s
+    };
+    assert_eq!(read("dt=2024-05-28/part-00000.jsonl.gz"), "a\n");
+    assert_eq!(read("dt=2024-05-28/part-00001.jsonl.gz"), "c\n");
+    assert_eq!(read("dt=2024-05-29/part-00000.jsonl.gz"), "b\n");
+
+    // Another run into the same directory continues after the existing parts
+    let mut files = PartitionedFiles::new(dir.clone(), "jsonl", 1);
+    files.write("dt=2024-05-28", "d").unwrap();
+    files.finish().unwrap();
+    assert_eq!(read("dt=2024-05-28/part-00000.jsonl.gz"), "a\n");
+    assert_eq!(read("dt=2024-05-28/part-00002.jsonl.gz"), "d\n");
+
+    // Only the least recently used writer is finished when too many are open
+    let mut files = PartitionedFiles::new(dir.join("lru"), "jsonl", u64::MAX);
+    for i in 0..MAX_OPEN_WRITERS {
+        files.write(&format!("p={i}"), "x").unwrap();
+    }
+    files.write("p=0", "x").unwrap();
+    files.write(&format!("p={MAX_OPEN_WRITERS}"), "x").unwrap();
+    files.write("p=0", "x").unwrap();
+    files.write("p=1", "x").unwrap();
+    assert_eq!(files.open, MAX_OPEN_WRITERS);
+    files.finish().unwrap();
+    assert_eq!(read("lru/p=0/part-00000.jsonl.gz"), "x\nx\nx\n");
+    assert_eq!(read("lru/p=1/part-00000.jsonl.gz"), "x\n");
+    assert_eq!(read("lru/p=1/part-00001.jsonl.gz"), "x\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,312 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::canonicalize;
+use std::iter;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter};
+use serde_json::{Map, Value};
+
+use super::{Line, Serialized};
+use crate::Type;
+use crate::parse::LBLogParser;
+use crate::record::Record;
+
+/// Records which are inserted in a single transaction
+const BATCH_SIZE: usize = 10_000;
+
+/// Columns which are indexed, on top of `file_id`
+const INDEXED: &[&str] = &["time", "client_ip", "elb_status_code"];
+
+/// Name of the table which logs of the given LB type are inserted into
+fn table_of<T: LBLogParser>() -> &'static str {
+    match T::TYPE {
+        Type::Alb => "alb",
+        Type::ClassicLb => "classic_lb",
+    }
+}
+
+/// Column type of a field. Timestamps are ISO 8601 texts, which SQLite date functions accept.
+fn column_type(name: &str) -> &'static str {
+    match name {
+        "client_port"
+        | "elb_status_code"
+        | "target_status_code"
+        | "backend_status_code"
+        | "received_bytes"
+        | "sent_bytes"
+        | "matched_rule_priority" => "INTEGER",
+        name if name.ends_with("_processing_time") => "REAL",
+        _ => "TEXT",
+    }
+}
+
+/// Column definitions of the fields of a log, followed by `extra`
+pub(crate) fn column_definitions(fields: &[&str]) -> String {
+    let mut columns: Vec<_> = fields
+        .iter()
+        .map(|name| format!("\"{name}\" {}", column_type(name)))
+        .collect();
+    columns.push("extra TEXT".to_owned());
+    columns.join(", ")
+}
+
+/// Values of the columns of a record. Fields other than those of the log, e.g. those added by the
+/// Enrichment options, are kept as a JSON object in the `extra` column.
+pub(crate) fn row_of(fields: &[&str], record: &Record) -> Vec<SqlValue> {
+    let mut row = Vec::with_capacity(fields.len() + 1);
+    for name in fields {
+        row.push(match record.get(name) {
+            Some(value) => to_sql(value, column_type(name)),
+            None => SqlValue::Null,
+        });
+    }
+    let extra: Map<String, Value> = record
+        .iter()
+        .filter(|(name, _)| !fields.contains(name))
+        .map(|(name, value)| (name.to_owned(), value.clone()))
+        .collect();
+    row.push(match extra.is_empty() {
+        true => SqlValue::Null,
+        false => SqlValue::Text(Value::Object(extra).to_string()),
+    });
+    row
+}
+
+/// Table of an SQLite database which records are inserted into, one row per record. Records are
+/// inserted in batches of transactions.
+///
+/// Every source file is numbered in the `files` table, and each row refers to the file which it
+/// came from by `file_id`. When a file is loaded again, rows previously loaded from the file are
+/// replaced, so that appending to an existing database never duplicates them. They are removed in
+/// the transaction which inserts the new rows, which is not committed until every row of the file
+/// is inserted, so that a failed run never leaves a file with only a part of its rows.
+pub(crate) struct SqliteTable {
+    conn: Connection,
+    table: &'static str,
+    insert: String,
+    file_ids: HashMap<String, i64>,
+    /// Files whose previous rows were removed, but not every new row of which is inserted yet
+    replacing: HashSet<String>,
+    pending: usize,
+}
+
+impl SqliteTable {
+    pub(crate) fn open<T: LBLogParser>(path: &Path) -> Result<Self> {
+        let table = table_of::<T>();
+        let conn =
+            Connection::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut schema = format!(
+            "CREATE TABLE IF NOT EXISTS files (id INTEGER PRIMARY KEY, path TEXT NOT NULL UNIQUE);
+            CREATE TABLE IF NOT EXISTS {table} (
+                file_id INTEGER NOT NULL REFERENCES files (id),
+                {}
+            );
+            CREATE INDEX IF NOT EXISTS {table}_file_id ON {table} (file_id);",
+            column_definitions(T::FIELDS),
+        );
+        for column in INDEXED {
+            schema +=
+                &format!("CREATE INDEX IF NOT EXISTS {table}_{column} ON {table} (\"{column}\");");
+        }
+        conn.execute_batch(&schema)
+            .with_context(|| format!("Failed to create table {table} in {}", path.display()))?;
+
+        let insert = format!(
+            "INSERT INTO {table} VALUES (?{})",
+            ", ?".repeat(T::FIELDS.len() + 1)
+        );
+        Ok(Self {
+            conn,
+            table,
+            insert,
+            file_ids: HashMap::new(),
+            replacing: HashSet::new(),
+            pending: 0,
+        })
+    }
+
+    /// Inserts the row of a record.
+    pub(crate) fn write(&mut self, line: &Line) -> Result<()> {
+        if self.conn.is_autocommit() {
+            self.conn.execute_batch("BEGIN")?;
+        }
+        match &line.record {
+            Serialized::Row(row) => {
+                let file_id = SqlValue::Integer(self.file_id(&line.source)?);
+                self.conn
+                    .prepare_cached(&self.insert)?
+                    .execute(params_from_iter(iter::once(&file_id).chain(row)))?;
+                self.pending += 1;
+            }
+            Serialized::FileStart => {
+                self.file_id(&line.source)?;
+            }
+            Serialized::FileEnd => {
+                self.replacing.remove(&*line.source);
+            }
+            Serialized::Text(_) => unreachable!("--format sqlite serializes rows"),
+        }
+
+        if self.pending >= BATCH_SIZE && self.replacing.is_empty() {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Commits the rows inserted so far, unless a file being replaced is not complete, i.e. the
+    /// run failed, in which case its previous rows are kept by rolling back.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if !self.replacing.is_empty() && !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+            return Ok(());
+        }
+        self.commit()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Numbers a source file by its canonical path, removing rows previously loaded from it when
+    /// it is seen for the first time. Stdin, `-`, is never deduplicated.
+    fn file_id(&mut self, source: &str) -> Result<i64> {
+        if let Some(id) = self.file_ids.get(source) {
+            return Ok(*id);
+        }
+        let path = match source {
+            "-" => source.to_owned(),
+            _ => canonicalize(source).map_or_else(
+                |_| source.to_owned(),
+                |path| path.to_string_lossy().into_owned(),
+            ),
+        };
+        let existing: Option<i64> = self
+            .conn
+            .query_row("SELECT id FROM files WHERE path = ?1", [&path], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let id = match existing {
+            Some(id) if source != "-" => {
+                let removed = self.conn.execute(
+                    &format!("DELETE FROM {} WHERE file_id = ?1", self.table),
+                    params![id],
+                )?;
+                if removed > 0 {
+                    eprintln!("Replacing {removed} rows previously loaded from {path}");
+                }
+                self.replacing.insert(source.to_owned());
+                id
+            }
+            Some(id) => id,
+            None => {
+                self.conn
+                    .execute("INSERT INTO files (path) VALUES (?1)", [&path])?;
+                self.conn.last_insert_rowid()
+            }
+        };
+        self.file_ids.insert(source.to_owned(), id);
+        Ok(id)
+    }
+}
+
+/// Converts a field of a record into the type of its column, where "-" is NULL.
+fn to_sql(value: &Value, column_type: &str) -> SqlValue {
+    let text = |s: &str| SqlValue::Text(s.to_owned());
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::String(s) if s == "-" => SqlValue::Null,
+        Value::String(s) => match column_type {
+            "INTEGER" => s.parse().map_or_else(|_| text(s), SqlValue::Integer),
+            "REAL" => s.parse().map_or_else(|_| text(s), SqlValue::Real),
+            _ => text(s),
+        },
+        Value::Bool(b) => SqlValue::Integer((*b).into()),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => n.as_f64().map_or(SqlValue::Null, SqlValue::Real),
+        },
+        value => SqlValue::Text(value.to_string()),
+    }
+}
+
+#[test]
+fn test_sqlite_table() {
+    use crate::alb::LogParser;
+
+    let dir = std::env::temp_dir().join(format!("elb-log-parser-sqlite-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let db = dir.join("incident.db");
+    let source = dir.join("a.log.gz");
+    std::fs::write(&source, b"").unwrap();
+    let line = |record| Line {
+        partition: String::new(),
+        source: source.to_string_lossy().into(),
+        record,
+    };
+    let mut record = Record::default();
+    for (name, value) in [
+        ("time", "2024-05-28T13:00:00Z"),
+        ("client_port", "443"),
+        ("elb_status_code", "503"),
+        ("target_processing_time", "-1"),
+        ("target_status_code", "-"),
+    ] {
+        record.insert(name, value);
+    }
+    record.insert("geoip", serde_json::json!({ "country": "KR" }));
+    let row = row_of(LogParser::FIELDS, &record);
+
+    // Loading the same file twice replaces the rows of the first load
+    for _ in 0..2 {
+        let mut table = SqliteTable::open::<LogParser>(&db).unwrap();
+        table.write(&line(Serialized::FileStart)).unwrap();
+        table.write(&line(Serialized::Row(row.clone()))).unwrap();
+        table.write(&line(Serialized::FileEnd)).unwrap();
+        table.finish().unwrap();
+    }
+
+    // A reload which failed before the end of the file keeps the previous rows
+    let mut table = SqliteTable::open::<LogParser>(&db).unwrap();
+    table.write(&line(Serialized::FileStart)).unwrap();
+    table.finish().unwrap();
+
+    let conn = Connection::open(&db).unwrap();
+    let row: (i64, i64, String, f64, Option<i64>, String) = conn
+        .query_row(
+            "SELECT count(*), client_port, typeof(elb_status_code), target_processing_time, target_status_code, extra FROM alb",
+            [],
+            |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        row,
+        (
+            1,
+            443,
+            "integer".to_owned(),
+            -1.0,
+            None,
+            r#"{"geoip":{"country":"KR"}}"#.to_owned()
+        )
+    );
+
+    // So does loading it again when it has no rows now
+    let mut table = SqliteTable::open::<LogParser>(&db).unwrap();
+    table.write(&line(Serialized::FileStart)).unwrap();
+    table.write(&line(Serialized::FileEnd)).unwrap();
+    table.finish().unwrap();
+    let count: i64 = conn
+        .query_row("SELECT count(*) FROM alb", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,45 +2,107 @@ use serde::{Serialize, Serializer, ser};
 use thiserror::Error;
 
 use crate::Type;
+use crate::diagnose::{Diagnoser, Diagnostic};
 
 #[derive(Error, Clone, Debug)]
 pub enum ParseLogError {
-    #[error("Invalid log line: {}", String::from_utf8_lossy(.0))]
-    InvalidLogFormat(Vec<u8>),
+    #[error("Invalid log line, {}: {}", .1.diagnose(.0), String::from_utf8_lossy(.0))]
+    InvalidLogFormat(Vec<u8>, &'static Diagnoser),
+    #[error("Invalid log line, {}: {}", .1, String::from_utf8_lossy(.0))]
+    InvalidUtf8(Vec<u8>, Diagnostic),
+    #[error("Invalid log line, {} (the line is not shown with --anonymize)", .0.location())]
+    Redacted(Diagnostic),
+}
+
+impl ParseLogError {
+    /// Finds which field of the line failed to parse.
+    pub(crate) fn diagnostic(&self) -> Diagnostic {
+        match self {
+            ParseLogError::InvalidLogFormat(log, diagnoser) => diagnoser.diagnose(log),
+            ParseLogError::InvalidUtf8(_, diagnostic) | ParseLogError::Redacted(diagnostic) => {
+                diagnostic.clone()
+            }
+        }
+    }
+
+    /// Raw bytes of the line which failed to parse, which are empty once redacted
+    pub(crate) fn line(&self) -> &[u8] {
+        match self {
+            ParseLogError::InvalidLogFormat(log, _) | ParseLogError::InvalidUtf8(log, _) => log,
+            ParseLogError::Redacted(_) => &[],
+        }
+    }
+
+    /// Drops the line, which must not be reported when records are anonymized, and keeps only
+    /// where it failed to parse.
+    pub(crate) fn redact(self) -> Self {
+        let diagnostic = self.diagnostic();
+        ParseLogError::Redacted(Diagnostic {
+            position: None,
+            found: Vec::new(),
+            ..diagnostic
+        })
+    }
 }
 
 pub(crate) trait LBLogParser {
-    type Log<'input>: Serialize;
+    type Log<'input>: Serialize + LogFields;
 
     const EXT: &'static str;
     const TYPE: Type;
     const REGEX: &'static str;
+    /// Name of the field captured by each capture group of `REGEX`, in order
+    const FIELDS: &'static [&'static str];
+    /// Syntax of a log line, where `{name}` is a field and `[...]` is rendered only if the fields
+    /// in it exist
+    const TEMPLATE: &'static str;
 
     fn new() -> Self;
     fn parse<'input>(&self, log: &'input [u8]) -> Result<Self::Log<'input>, ParseLogError>;
+}
 
-    fn try_find_failed_position(log: &[u8]) -> Option<usize> {
-        use regex_automata::Input;
-        use regex_automata::dfa::{Automaton, dense::DFA};
-
-        let dfa = DFA::new(Self::REGEX).unwrap();
-        let mut s = dfa.start_state_forward(&Input::new(log)).unwrap();
-
-        for (idx, &byte) in log.iter().enumerate() {
-            s = dfa.next_state(s, byte);
-            if dfa.is_dead_state(s) {
-                return Some(idx);
-            }
-        }
-        s = dfa.next_eoi_state(s);
-        if dfa.is_dead_state(s) {
-            return Some(log.len());
-        }
+pub(crate) trait LogFields {
+    /// Returns raw bytes of every field, in the same order as `LBLogParser::FIELDS`.
+    fn fields(&self) -> Vec<Option<&[u8]>>;
 
+    /// Returns raw bytes named in `LBLogParser::TEMPLATE` which are not fields, e.g. undocumented
+    /// spaces, so that the original line can be rendered back.
+    fn filler(&self, _name: &str) -> Option<&[u8]> {
         None
     }
 }
 
+/// Fails like a parse error if any field of `log`, parsed from `line`, is not valid UTF-8. This is
+/// how `--invalid-utf8 error` rejects such lines before they are serialized.
+pub(crate) fn check_utf8<'input, T: LBLogParser>(
+    line: &'input [u8],
+    log: T::Log<'input>,
+) -> Result<T::Log<'input>, ParseLogError> {
+    // Fields are looked into only if the line is not valid UTF-8 as a whole, which is rare
+    if std::str::from_utf8(line).is_ok() {
+        return Ok(log);
+    }
+    let invalid = log
+        .fields()
+        .into_iter()
+        .enumerate()
+        .find_map(|(idx, field)| {
+            let field = field?;
+            let err = std::str::from_utf8(field).err()?;
+            let offset = field.as_ptr().addr() - line.as_ptr().addr();
+            Some(Diagnostic {
+                position: Some(offset + err.valid_up_to()),
+                field: Some((idx + 1, T::FIELDS[idx])),
+                expected: "valid UTF-8, see --invalid-utf8".to_owned(),
+                found: field.to_owned(),
+            })
+        });
+    match invalid {
+        Some(diagnostic) => Err(ParseLogError::InvalidUtf8(line.to_owned(), diagnostic)),
+        None => Ok(log),
+    }
+}
+
 pub(crate) fn bytes_ser<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
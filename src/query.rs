@@ -0,0 +1,413 @@
+use std::borrow::Cow;
+use std::ffi::{CStr, CString, c_int};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Error, Result, bail};
+use crossbeam_channel::{Receiver, Sender, bounded, unbounded};
+use jiff::civil::DateTime;
+use jiff::fmt::temporal::Pieces;
+use jiff::tz::TimeZone;
+use jiff::{SignedDuration, Timestamp};
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::vtab::{
+    Context as SqlContext, Filters, IndexConstraintOp, IndexInfo, Module, VTab, VTabConnection,
+    VTabCursor,
+};
+use rusqlite::{Connection, ffi};
+use serde_json::Value;
+use walkdir::WalkDir;
+
+use crate::output::{Line, Serialized, column_definitions};
+use crate::parse::LBLogParser;
+use crate::{Context, walkdir};
+
+/// Longest interval of log files, which is 60 minutes for Classic LBs
+const MAX_INTERVAL: SignedDuration = SignedDuration::from_mins(60);
+
+/// Allowance for logs whose time is outside the interval of the file they are written into, e.g.
+/// those of long-running requests
+const SKEW: SignedDuration = SignedDuration::from_mins(60);
+
+/// Records buffered between a scan and the cursor which reads it
+const ROWS_BUFFER: usize = 1024;
+
+/// Range of `time` which a scan of the `logs` table is constrained to, both ends inclusive.
+/// Unbounded by default.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct TimeRange {
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+}
+
+impl TimeRange {
+    /// Whether a log file may contain logs in the range, judging by the end of the interval in
+    /// its name, e.g. `..._app.my-alb.50dc6c495c0c9188_20240528T1305Z_10.0.0.1_2et2e1mx.log.gz`.
+    /// Files without one are never pruned.
+    pub(crate) fn may_contain(&self, path: &Path) -> bool {
+        let Some(end) = interval_end(path) else {
+            return true;
+        };
+        self.start
+            .is_none_or(|start| start.duration_since(end) <= SKEW)
+            && self
+                .end
+                .is_none_or(|time| end.duration_since(time) <= MAX_INTERVAL + SKEW)
+    }
+}
+
+fn interval_end(path: &Path) -> Option<Timestamp> {
+    let name = path.file_name()?.to_str()?;
+    name.split('_').find_map(|part| {
+        let end = DateTime::strptime("%Y%m%dT%H%MZ", part).ok()?;
+        Some(end.to_zoned(TimeZone::UTC).ok()?.timestamp())
+    })
+}
+
+/// Parses the value compared with `time`, e.g. "2024-05-28T22:05:00+09:00", "2024-05-28T22:05"
+/// or "2024-05-28", as the time of day in `zone`, which `time` is rendered in. SQLite compares
+/// them as text, so any offset of the value is ignored as well.
+fn parse_time(s: &str, zone: &TimeZone) -> Option<Timestamp> {
+    let pieces = Pieces::parse(s).ok()?;
+    let time = pieces.date().to_datetime(pieces.time().unwrap_or_default());
+    Some(time.to_zoned(zone.clone()).ok()?.timestamp())
+}
+
+/// Runs a query over logs in `path`, which are exposed as the `logs` table, and writes each row
+/// of the result as a JSON object into `out`.
+///
+/// `path` is walked once, and every scan of the table parses its files on its own worker threads,
+/// skipping those which cannot contain logs in the range of `time` which the query is constrained
+/// to. Files are pruned only if `time` is rendered in RFC 3339, which SQLite compares as text.
+pub(crate) fn run<T: LBLogParser>(
+    sql: &str,
+    path: &str,
+    ctx: &Context,
+    out: impl Write,
+) -> Result<()> {
+    if path == "-" {
+        bail!("Logs cannot be queried from stdin, which can be scanned only once");
+    }
+    let files = WalkDir::new(path)
+        .into_iter()
+        .collect::<walkdir::Result<Vec<_>>>()?;
+    let files = &files;
+    let (requests, received) = unbounded::<Scan>();
+    let scanner = Scanner {
+        fields: T::FIELDS,
+        zone: ctx.config.enrich.rfc3339_zone(),
+        requests,
+        error: Arc::new(Mutex::new(None)),
+    };
+    let error = scanner.error.clone();
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            for Scan {
+                range,
+                rows,
+                result,
+            } in received
+            {
+                scope.spawn(move || {
+                    let entries = files
+                        .iter()
+                        .filter(|entry| range.may_contain(entry.path()))
+                        .map(|entry| Ok(entry.clone()));
+                    let scanned = walkdir::<T>(entries, ctx, |lines| {
+                        // Stops once the cursor is closed, e.g. by LIMIT
+                        for line in lines {
+                            if rows.send(line).is_err() {
+                                break;
+                            }
+                        }
+                        Ok(())
+                    });
+                    let _ = result.send(scanned);
+                });
+            }
+        });
+        execute(sql, scanner, out).map_err(|err| error.lock().unwrap().take().unwrap_or(err))
+    })
+}
+
+fn execute(sql: &str, scanner: Scanner, out: impl Write) -> Result<()> {
+    const MODULE: Module<LogsTab> = Module::eponymous_only_module();
+    let conn = Connection::open_in_memory()?;
+    conn.create_module("logs", &MODULE, Some(scanner))?;
+
+    let mut stmt = conn.prepare(sql)?;
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut rows = stmt.query([])?;
+    let mut out = BufWriter::new(out);
+    while let Some(row) = rows.next()? {
+        // Keys are written in the order of the columns, which `Map` would sort
+        write!(out, "{{")?;
+        for (idx, name) in names.iter().enumerate() {
+            let value = match row.get_ref(idx)? {
+                ValueRef::Null => Value::Null,
+                ValueRef::Integer(i) => i.into(),
+                ValueRef::Real(f) => f.into(),
+                ValueRef::Text(s) | ValueRef::Blob(s) => String::from_utf8_lossy(s).into(),
+            };
+            let separator = if idx == 0 { "" } else { "," };
+            write!(out, "{separator}{}:{value}", Value::from(name.as_str()))?;
+        }
+        writeln!(out, "}}")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Request for a scan of the `logs` table, whose records are sent to `rows` until the scan
+/// finishes with `result`
+struct Scan {
+    range: TimeRange,
+    rows: Sender<Line>,
+    result: Sender<Result<()>>,
+}
+
+#[derive(Clone)]
+struct Scanner {
+    fields: &'static [&'static str],
+    /// Time zone of `time` if it is rendered in RFC 3339, otherwise files are never pruned
+    zone: Option<TimeZone>,
+    requests: Sender<Scan>,
+    /// First error of the scans, which is reported instead of the error of SQLite
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+/// The `logs` virtual table, whose columns are the fields of a log followed by `extra` and `file`
+#[repr(C)]
+struct LogsTab {
+    /// Base class, which must come first
+    base: ffi::sqlite3_vtab,
+    scanner: Scanner,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for LogsTab {
+    type Aux = Scanner;
+    type Cursor = LogsCursor<'vtab>;
+
+    fn connect(
+        _: &mut VTabConnection,
+        aux: Option<&Scanner>,
+        _: &[u8],
+        _: &[u8],
+        _: &[u8],
+        _: &[&[u8]],
+    ) -> rusqlite::Result<(Cow<'static, CStr>, Self)> {
+        let scanner = aux
+            .expect("logs table is registered with a scanner")
+            .clone();
+        let schema = format!(
+            "CREATE TABLE x({}, file TEXT)",
+            column_definitions(scanner.fields)
+        );
+        let schema = CString::new(schema).map_err(module_error)?;
+        let vtab = Self {
+            base: ffi::sqlite3_vtab::default(),
+            scanner,
+        };
+        Ok((Cow::Owned(schema), vtab))
+    }
+
+    /// Passes comparisons of `time` to the cursor, in the order of their operators in `idx_str`,
+    /// e.g. "><" for `time >= ? AND time < ?`. SQLite still checks them on every row, since
+    /// they only prune files.
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<bool> {
+        let time = match self.scanner.zone {
+            Some(_) => self.scanner.fields.iter().position(|name| *name == "time"),
+            None => None,
+        };
+        let comparisons: Vec<_> = info
+            .constraints()
+            .enumerate()
+            .filter(|(_, constraint)| {
+                constraint.is_usable() && Some(constraint.column() as usize) == time
+            })
+            .filter_map(|(idx, constraint)| match constraint.operator() {
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GT
+                | IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GE => Some((idx, '>')),
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LT
+                | IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LE => Some((idx, '<')),
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ => Some((idx, '=')),
+                _ => None,
+            })
+            .collect();
+        let mut operators = String::new();
+        for (argv_idx, (idx, operator)) in comparisons.into_iter().enumerate() {
+            info.constraint_usage(idx)
+                .set_argv_index(argv_idx as c_int + 1);
+            operators.push(operator);
+        }
+        info.set_estimated_cost(if operators.is_empty() { 1e9 } else { 1e6 });
+        info.set_idx_str(&operators);
+        Ok(true)
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<LogsCursor<'vtab>> {
+        Ok(LogsCursor {
+            base: ffi::sqlite3_vtab_cursor::default(),
+            tab: self,
+            rows: None,
+            result: None,
+            row: Vec::new(),
+            rowid: 0,
+        })
+    }
+}
+
+#[repr(C)]
+struct LogsCursor<'vtab> {
+    /// Base class, which must come first
+    base: ffi::sqlite3_vtab_cursor,
+    tab: &'vtab LogsTab,
+    /// Records of the current scan, which is closed once it is exhausted
+    rows: Option<Receiver<Line>>,
+    result: Option<Receiver<Result<()>>>,
+    row: Vec<SqlValue>,
+    rowid: i64,
+}
+
+unsafe impl VTabCursor for LogsCursor<'_> {
+    fn filter(
+        &mut self,
+        _: c_int,
+        idx_str: Option<&str>,
+        args: &Filters<'_>,
+    ) -> rusqlite::Result<()> {
+        let mut range = TimeRange::default();
+        let zone = self.tab.scanner.zone.as_ref();
+        for (idx, operator) in idx_str.unwrap_or_default().chars().enumerate() {
+            let value = args.get::<Option<String>>(idx).ok().flatten();
+            let time = value.as_deref().zip(zone);
+            let Some(time) = time.and_then(|(value, zone)| parse_time(value, zone)) else {
+                continue;
+            };
+            if operator != '<' {
+                range.start = range.start.max(Some(time));
+            }
+            if operator != '>' {
+                range.end = Some(range.end.map_or(time, |end| end.min(time)));
+            }
+        }
+
+        let (rows, rx) = bounded(ROWS_BUFFER);
+        let (result, result_rx) = bounded(1);
+        let scan = Scan {
+            range,
+            rows,
+            result,
+        };
+        self.tab.scanner.requests.send(scan).map_err(module_error)?;
+        self.rows = Some(rx);
+        self.result = Some(result_rx);
+        self.rowid = 0;
+        self.next()
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        let Some(rows) = &self.rows else {
+            return Ok(());
+        };
+        while let Ok(line) = rows.recv() {
+            let Serialized::Row(row) = line.record else {
+                continue;
+            };
+            self.row = row;
+            self.row.push(SqlValue::Text(line.source.to_string()));
+            self.rowid += 1;
+            return Ok(());
+        }
+
+        self.rows = None;
+        match self.result.take().map(|result| result.recv()) {
+            Some(Ok(Err(err))) => {
+                let message = format!("{err:#}");
+                self.tab.scanner.error.lock().unwrap().get_or_insert(err);
+                Err(rusqlite::Error::ModuleError(message))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn eof(&self) -> bool {
+        self.rows.is_none()
+    }
+
+    fn column(&self, ctx: &mut SqlContext, idx: c_int) -> rusqlite::Result<()> {
+        ctx.set_result(&self.row[idx as usize])
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        Ok(self.rowid)
+    }
+}
+
+fn module_error(err: impl ToString) -> rusqlite::Error {
+    rusqlite::Error::ModuleError(err.to_string())
+}
+
+#[test]
+fn test_time_range() {
+    let file = Path::new(
+        "123456789012_elasticloadbalancing_us-east-1_app.my-alb.50dc6c495c0c9188_20240528T1305Z_10.0.0.1_2et2e1mx.log.gz",
+    );
+    let range = |start: &str, end: &str| TimeRange {
+        start: parse_time(start, &TimeZone::UTC),
+        end: parse_time(end, &TimeZone::UTC),
+    };
+    assert!(TimeRange::default().may_contain(file));
+    assert!(range("2024-05-28T13:00:00Z", "2024-05-28T13:05:00Z").may_contain(file));
+    assert!(range("2024-05-28", "2024-05-29").may_contain(file));
+    assert!(!range("2024-05-29", "2024-05-30").may_contain(file));
+    assert!(!range("2024-05-27", "2024-05-28T10:00").may_contain(file));
+    assert!(range("2024-05-29", "2024-05-30").may_contain(Path::new("a.log.gz")));
+    assert_eq!(parse_time("yesterday", &TimeZone::UTC), None);
+    // Values are read in the zone of `time`, whatever their offset
+    let seoul = TimeZone::get("Asia/Seoul").unwrap();
+    assert_eq!(
+        parse_time("2024-05-28T22:00:00+00:00", &seoul),
+        parse_time("2024-05-28T13:00", &TimeZone::UTC)
+    );
+}
+
+#[test]
+fn test_query_with_tz() {
+    use clap::Parser;
+
+    use crate::ClassicLBLogParser;
+    use crate::format::Format;
+
+    let dir = std::env::temp_dir().join(format!("elb-log-parser-query-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("123456789012_elasticloadbalancing_us-west-2_my-loadbalancer_20240528T1305Z_172.160.001.192_20sg8hgm.log"),
+        br#"2024-05-28T13:04:14.804475Z my-loadbalancer 192.168.131.39:2817 10.0.0.1:80 0.000073 0.001048 0.000057 200 200 0 29 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.38.0" - -
+"#,
+    )
+    .unwrap();
+    let mut config =
+        crate::Config::try_parse_from(["elb-log-parser", "--tz", "Asia/Seoul"]).unwrap();
+    config.output.format = Format::Sqlite;
+    let ctx = Context::new(config, None).unwrap();
+    let query = |sql: &str| {
+        let mut out = Vec::new();
+        run::<ClassicLBLogParser>(sql, dir.to_str().unwrap(), &ctx, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    // The file ends at 22:05 in Seoul, so it is not pruned
+    assert_eq!(
+        query("SELECT time FROM logs WHERE time >= '2024-05-28T22:00'"),
+        "{\"time\":\"2024-05-28T22:04:14.804475+09:00\"}\n"
+    );
+    assert_eq!(
+        query("SELECT time FROM logs WHERE time >= '2024-05-29T22:00'"),
+        ""
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
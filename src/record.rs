@@ -0,0 +1,140 @@
+use std::borrow::Cow;
+
+use anyhow::{Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use clap::ValueEnum;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::Value;
+
+use crate::parse::{LBLogParser, LogFields};
+use crate::unescape::{ESCAPED_FIELDS, unescape};
+
+/// How to handle fields which are not valid UTF-8.
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) enum InvalidUtf8 {
+    /// Fail the whole record
+    #[default]
+    Error,
+    /// Replace invalid bytes with U+FFFD
+    Lossy,
+    /// Escape invalid bytes as "\xHH", as AWS does
+    Escape,
+    /// Encode the whole field in base64
+    Base64,
+}
+
+/// How raw bytes of the fields are decoded into strings
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Decoding {
+    pub(crate) invalid_utf8: InvalidUtf8,
+    /// Decode escape sequences in `ESCAPED_FIELDS`
+    pub(crate) unescape: bool,
+}
+
+/// Log line converted into an ordered list of named fields, so that derived fields can be added
+/// to it before serialization.
+#[derive(Default, Debug)]
+pub(crate) struct Record {
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl Record {
+    /// Converts every field of the log into a string. Optional fields missing from the log are
+    /// omitted, as `Log` does when serialized.
+    ///
+    /// Names of the fields which were not valid UTF-8 are listed in `invalid_utf8_fields`.
+    pub(crate) fn from_log<T: LBLogParser>(log: &T::Log<'_>, decoding: Decoding) -> Result<Self> {
+        let mut record = Record::default();
+        let mut invalid_fields = Vec::new();
+        for (&name, bytes) in T::FIELDS.iter().zip(log.fields()) {
+            let Some(mut bytes) = bytes.map(Cow::Borrowed) else {
+                continue;
+            };
+            if decoding.unescape && ESCAPED_FIELDS.contains(&name) {
+                bytes = Cow::Owned(unescape(&bytes, T::TYPE).into_owned());
+            }
+            let value = match std::str::from_utf8(&bytes) {
+                Ok(str) => str.to_owned(),
+                Err(_) => {
+                    invalid_fields.push(Value::from(name));
+                    decode_invalid_utf8(name, &bytes, decoding.invalid_utf8)?
+                }
+            };
+            record.fields.push((name, Value::String(value)));
+        }
+        if !invalid_fields.is_empty() {
+            record.insert("invalid_utf8_fields", Value::Array(invalid_fields));
+        }
+        Ok(record)
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    pub(crate) fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(Value::as_str)
+    }
+
+    /// Inserts a field at the end of the record, or replaces it if it already exists.
+    pub(crate) fn insert(&mut self, name: &'static str, value: impl Into<Value>) {
+        let value = value.into();
+        match self.fields.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => self.fields.push((name, value)),
+        }
+    }
+
+    /// Iterates over every field, in order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&'static str, &Value)> {
+        self.fields.iter().map(|(name, value)| (*name, value))
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| *n != name);
+    }
+}
+
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for (name, value) in &self.fields {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+fn decode_invalid_utf8(name: &str, bytes: &[u8], mode: InvalidUtf8) -> Result<String> {
+    Ok(match mode {
+        InvalidUtf8::Error => bail!("field `{name}` contains invalid UTF-8 characters"),
+        InvalidUtf8::Lossy => String::from_utf8_lossy(bytes).into_owned(),
+        InvalidUtf8::Escape => bytes
+            .utf8_chunks()
+            .flat_map(|chunk| {
+                let invalid = chunk.invalid().iter().map(|b| format!("\\x{b:02X}"));
+                [chunk.valid().to_owned()].into_iter().chain(invalid)
+            })
+            .collect(),
+        InvalidUtf8::Base64 => BASE64.encode(bytes),
+    })
+}
+
+#[test]
+fn test_decode_invalid_utf8() {
+    let bytes = b"caf\xC3\xA9 \xFF\xFEx";
+    assert!(decode_invalid_utf8("url", bytes, InvalidUtf8::Error).is_err());
+    assert_eq!(
+        decode_invalid_utf8("url", bytes, InvalidUtf8::Lossy).unwrap(),
+        "caf\u{E9} \u{FFFD}\u{FFFD}x"
+    );
+    assert_eq!(
+        decode_invalid_utf8("url", bytes, InvalidUtf8::Escape).unwrap(),
+        "caf\u{E9} \\xFF\\xFEx"
+    );
+    assert_eq!(
+        decode_invalid_utf8("url", bytes, InvalidUtf8::Base64).unwrap(),
+        "Y2Fmw6kg//54"
+    );
+}
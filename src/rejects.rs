@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/// Dead-letter file which collects every line that failed to parse.
+///
+/// Each rejected line is written as `<line number>\t<source>\t<raw line>`, so the original bytes can
+/// be recovered with `cut -f3-` and fed back into the parser. The line number comes first, since
+/// the source may contain any character but a tab. If the path ends with `.gz`, the file is gzip
+/// compressed.
+pub(crate) struct Rejects {
+    writer: Mutex<Writer>,
+}
+
+enum Writer {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Rejects {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let writer = if path.extension().is_some_and(|ext| ext == "gz") {
+            Writer::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            Writer::Plain(file)
+        };
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub(crate) fn write(&self, source: &str, line_number: u64, line: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let writer: &mut dyn Write = match &mut *writer {
+            Writer::Plain(w) => w,
+            Writer::Gzip(w) => w,
+        };
+        write!(writer, "{line_number}\t{source}\t")?;
+        writer.write_all(line.strip_suffix(b"\n").unwrap_or(line))?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        match self.writer.into_inner().unwrap() {
+            Writer::Plain(mut w) => w.flush()?,
+            Writer::Gzip(w) => w.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_rejects() {
+    let path = std::env::temp_dir().join(format!("elb-log-parser-rejects-{}", std::process::id()));
+    let rejects = Rejects::create(&path).unwrap();
+    rejects.write("logs/a:b.log", 3, b"broken line\n").unwrap();
+    rejects.write("-", 10, b"another\tline").unwrap();
+    rejects.finish().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "3\tlogs/a:b.log\tbroken line\n10\t-\tanother\tline\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
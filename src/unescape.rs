@@ -0,0 +1,125 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+
+use crate::Type;
+
+/// Fields in which load balancers escape quotes, backslashes, and non-printable or non-ASCII
+/// bytes
+pub(crate) const ESCAPED_FIELDS: &[&str] = &["url", "user_agent", "redirect_url", "trace_id"];
+
+/// Decodes escape sequences written by the given type of load balancer back into the original
+/// bytes, which `escape` reverts.
+///
+/// - `\xHH` is a single byte for ALB, e.g. `\xEC\x97\x90` is "에" in UTF-8
+/// - `\xHHHHHHHH` is a code point for Classic LB, e.g. `\x00000022` is `"`
+/// - `\"` and `\\` are a quote and a backslash
+///
+/// Anything else is left as is.
+pub(crate) fn unescape(bytes: &[u8], r#type: Type) -> Cow<'_, [u8]> {
+    if !bytes.contains(&b'\\') {
+        return Cow::Borrowed(bytes);
+    }
+
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        match rest {
+            [b'\\', b'"' | b'\\', ..] => {
+                out.push(rest[1]);
+                i += 2;
+            }
+            [b'\\', b'x', ..] => match r#type {
+                Type::Alb if let Some(byte) = hex(&rest[2..], 2) => {
+                    out.push(byte as u8);
+                    i += 4;
+                }
+                Type::ClassicLb if let Some(c) = hex(&rest[2..], 8).and_then(char::from_u32) => {
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    i += 10;
+                }
+                _ => {
+                    out.push(b'\\');
+                    i += 1;
+                }
+            },
+            _ => {
+                out.push(rest[0]);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Escapes a field as the load balancer would, which `unescape` reverts.
+///
+/// Quotes, backslashes, and characters which are not printable ASCII are escaped. ALB escapes
+/// each byte of UTF-8 as `\xHH` and quotes as `\"`, while Classic LB escapes every code point
+/// as `\xHHHHHHHH`.
+pub(crate) fn escape(s: &str, r#type: Type) -> Cow<'_, str> {
+    if s.bytes()
+        .all(|b| matches!(b, 0x20..=0x7E) && b != b'"' && b != b'\\')
+    {
+        return Cow::Borrowed(s);
+    }
+
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match (c, &r#type) {
+            ('\\', _) => out.push_str(r"\\"),
+            ('"', Type::Alb) => out.push_str(r#"\""#),
+            (' '..='~', _) if c != '"' => out.push(c),
+            (_, Type::Alb) => {
+                for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                    write!(out, r"\x{b:02X}").unwrap();
+                }
+            }
+            (_, Type::ClassicLb) => write!(out, r"\x{:08x}", u32::from(c)).unwrap(),
+        }
+    }
+    Cow::Owned(out)
+}
+
+fn hex(bytes: &[u8], len: usize) -> Option<u32> {
+    let digits = std::str::from_utf8(bytes.get(..len)?).ok()?;
+    if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[test]
+fn test_unescape() {
+    let t = |input: &[u8], r#type, expected: &[u8]| {
+        assert_eq!(unescape(input, r#type).as_ref(), expected)
+    };
+
+    t(b"curl/8.0", Type::Alb, b"curl/8.0");
+    t(
+        br"\xEC\x97\x90\xEC\x9D\xB4\xEC\xA0\x84\xED\x8A\xB8",
+        Type::Alb,
+        "에이전트".as_bytes(),
+    );
+    t(
+        br"\x00000022Mozilla/5.0\x00000022",
+        Type::ClassicLb,
+        br#""Mozilla/5.0""#,
+    );
+    t(br#"say \"hi\" \\ bye"#, Type::Alb, br#"say "hi" \ bye"#);
+    t(br"..\x5C..\x5Cwin.ini", Type::Alb, br"..\..\win.ini");
+    t(br"\xFF\x", Type::Alb, b"\xFF\\x");
+    // Each type decodes its own form only
+    t(br"\x00000022", Type::Alb, b"\x00000022");
+    t(br"\x5C", Type::ClassicLb, br"\x5C");
+
+    assert_eq!(escape("curl/8.0", Type::Alb), "curl/8.0");
+    assert_eq!(
+        escape(r#"에 "hi" \"#, Type::Alb),
+        r#"\xEC\x97\x90 \"hi\" \\"#
+    );
+    assert_eq!(
+        escape(r#""Mozilla/5.0""#, Type::ClassicLb),
+        r"\x00000022Mozilla/5.0\x00000022"
+    );
+}